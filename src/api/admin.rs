@@ -0,0 +1,446 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+use log::{error, info};
+
+use serde::Deserialize;
+
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::{delete, get, patch, post, Data, Outcome, State};
+use rocket_contrib::json::Json;
+
+use crate::chat;
+use crate::config::{Config, MAX_DVR_WINDOW};
+use crate::db::{RedisEntity, RedisMultiplexed};
+use crate::encoder::{joined_recording_path, recording_segments, EncodingConfiguration};
+use crate::moderation;
+use crate::tasks::encoding_queue;
+use crate::types::{EncodingJob, LatencyMode, StreamKey, Video, VideoStatus};
+
+/// Request guard that only lets through requests carrying `Authorization: Bearer <admin_token>`
+pub struct AdminToken;
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminToken {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let config = request.guard::<State<Arc<Config>>>()?;
+        let expected = format!("Bearer {}", config.admin_token);
+
+        match request.headers().get_one("Authorization") {
+            Some(value) if !config.admin_token.is_empty() && constant_time_eq(value, &expected) => {
+                Outcome::Success(AdminToken)
+            }
+            _ => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Compares the strings in a time that only depends on their length, so that the token can't be
+/// guessed one byte at a time
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewVideo {
+    title: String,
+    #[serde(default)]
+    description: String,
+    timestamp: u64,
+    #[serde(default)]
+    latency: LatencyMode,
+    dvr_window: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VideoPatch {
+    title: Option<String>,
+    description: Option<String>,
+    timestamp: Option<u64>,
+    /// One of the `thumbnails` extracted by the encoder
+    thumbnail: Option<String>,
+    latency: Option<LatencyMode>,
+    dvr_window: Option<u64>,
+}
+
+/// Largest thumbnail that can be uploaded, in bytes
+const THUMBNAIL_LIMIT: u64 = 2 * 1024 * 1024;
+
+fn internal_error<E: std::fmt::Debug>(e: E) -> Status {
+    error!("Admin API error: {:?}", e);
+
+    Status::InternalServerError
+}
+
+fn get_video(db: &RedisMultiplexed, id: String) -> Result<Video, Status> {
+    Video::sync_get(db, id)
+        .map_err(internal_error)?
+        .ok_or(Status::NotFound)
+}
+
+#[get("/videos")]
+pub fn list_videos(
+    _admin: AdminToken,
+    db: State<Arc<RedisMultiplexed>>,
+) -> Result<Json<HashMap<String, Video>>, Status> {
+    Ok(Json(Video::sync_list(&db).map_err(internal_error)?))
+}
+
+#[get("/videos/<id>")]
+pub fn video(
+    _admin: AdminToken,
+    db: State<Arc<RedisMultiplexed>>,
+    id: String,
+) -> Result<Json<Video>, Status> {
+    Ok(Json(get_video(&db, id)?))
+}
+
+#[post("/videos", data = "<input>")]
+pub fn create_video(
+    _admin: AdminToken,
+    db: State<Arc<RedisMultiplexed>>,
+    input: Json<NewVideo>,
+) -> Result<Json<Video>, Status> {
+    let input = input.into_inner();
+    if input.dvr_window > Some(MAX_DVR_WINDOW) {
+        return Err(Status::UnprocessableEntity);
+    }
+
+    let video = Video {
+        id: Video::generate_id(),
+        title: input.title,
+        description: input.description,
+        status: VideoStatus::Scheduled {
+            timestamp: input.timestamp,
+        },
+        thumbnail: None,
+        poster: None,
+        thumbnails: vec![],
+        latency: input.latency,
+        dvr_window: input.dvr_window,
+    };
+    video.sync_save(&db).map_err(internal_error)?;
+
+    info!("Scheduled video `{}`", video.id);
+
+    Ok(Json(video))
+}
+
+#[patch("/videos/<id>", data = "<input>")]
+pub fn edit_video(
+    _admin: AdminToken,
+    db: State<Arc<RedisMultiplexed>>,
+    id: String,
+    input: Json<VideoPatch>,
+) -> Result<Json<Video>, Status> {
+    let input = input.into_inner();
+    let mut video = get_video(&db, id)?;
+
+    if let Some(title) = input.title {
+        video.title = title;
+    }
+    if let Some(description) = input.description {
+        video.description = description;
+    }
+    if let Some(new_timestamp) = input.timestamp {
+        match video.status {
+            VideoStatus::Scheduled { ref mut timestamp } => *timestamp = new_timestamp,
+            // only scheduled videos can be moved around
+            _ => return Err(Status::Conflict),
+        }
+    }
+    if let Some(latency) = input.latency {
+        match video.status {
+            // the transcoder is already publishing to the other application
+            VideoStatus::Live { .. } if latency != video.latency => return Err(Status::Conflict),
+            _ => video.latency = latency,
+        }
+    }
+    if let Some(dvr_window) = input.dvr_window {
+        if dvr_window > MAX_DVR_WINDOW {
+            return Err(Status::UnprocessableEntity);
+        }
+        video.dvr_window = Some(dvr_window);
+    }
+    if let Some(thumbnail) = input.thumbnail {
+        if !video.thumbnails.contains(&thumbnail) {
+            return Err(Status::UnprocessableEntity);
+        }
+        video.thumbnail = Some(thumbnail);
+    }
+
+    video.sync_save(&db).map_err(internal_error)?;
+
+    Ok(Json(video))
+}
+
+/// Replaces the thumbnail of the video with the uploaded JPEG or PNG image
+#[post("/videos/<id>/thumbnail", data = "<data>")]
+pub fn upload_thumbnail(
+    _admin: AdminToken,
+    db: State<Arc<RedisMultiplexed>>,
+    config: State<Arc<Config>>,
+    id: String,
+    content_type: &ContentType,
+    data: Data,
+) -> Result<Json<Video>, Status> {
+    let mut video = get_video(&db, id)?;
+
+    let extension = if *content_type == ContentType::JPEG {
+        "jpg"
+    } else if *content_type == ContentType::PNG {
+        "png"
+    } else {
+        return Err(Status::UnsupportedMediaType);
+    };
+
+    let mut image = Vec::new();
+    data.open()
+        .take(THUMBNAIL_LIMIT + 1)
+        .read_to_end(&mut image)
+        .map_err(internal_error)?;
+    if image.len() as u64 > THUMBNAIL_LIMIT {
+        return Err(Status::PayloadTooLarge);
+    }
+
+    let encoded = Path::new(&config.storage_dir)
+        .join("encoded")
+        .join(&video.id);
+    std::fs::create_dir_all(&encoded).map_err(internal_error)?;
+
+    let filename = format!("custom.{}", extension);
+    std::fs::write(encoded.join(&filename), image).map_err(internal_error)?;
+
+    video.thumbnail = Some(filename);
+    video.sync_save(&db).map_err(internal_error)?;
+
+    info!("Uploaded thumbnail for video `{}`", video.id);
+
+    Ok(Json(video))
+}
+
+#[delete("/videos/<id>")]
+pub fn delete_video(
+    _admin: AdminToken,
+    db: State<Arc<RedisMultiplexed>>,
+    config: State<Arc<Config>>,
+    id: String,
+) -> Result<Status, Status> {
+    let video = get_video(&db, id)?;
+
+    match video.status {
+        // the final encoding isn't running anymore
+        VideoStatus::Provisional {
+            failed: Some(_), ..
+        } => {}
+        // the encoder would re-create the files and save the video again once done
+        VideoStatus::Live { .. } | VideoStatus::Processing | VideoStatus::Provisional { .. } => {
+            return Err(Status::Conflict)
+        }
+        _ => {}
+    }
+
+    for stream_key in stream_keys_for(&db, &video.id)? {
+        stream_key.sync_del(&db).map_err(internal_error)?;
+    }
+    if let Some(job) = EncodingJob::sync_get(&db, video.id.clone()).map_err(internal_error)? {
+        job.sync_del(&db).map_err(internal_error)?;
+    }
+    chat::sync_delete(&mut db.get_connection().map_err(internal_error)?, &video.id)
+        .map_err(internal_error)?;
+    moderation::sync_clear(&mut db.get_connection().map_err(internal_error)?, &video.id)
+        .map_err(internal_error)?;
+    video.sync_del(&db).map_err(internal_error)?;
+    remove_video_files(&config.storage_dir, &video.id).map_err(internal_error)?;
+
+    info!("Deleted video `{}`", video.id);
+
+    Ok(Status::NoContent)
+}
+
+/// Queues again the encoding of a video that failed
+#[post("/videos/<id>/retry")]
+pub fn retry_encoding(
+    _admin: AdminToken,
+    db: State<Arc<RedisMultiplexed>>,
+    id: String,
+) -> Result<Json<Video>, Status> {
+    let mut video = get_video(&db, id)?;
+    match video.status {
+        VideoStatus::Failed { .. }
+        | VideoStatus::Provisional {
+            failed: Some(_), ..
+        } => {}
+        _ => return Err(Status::Conflict),
+    }
+
+    let job = EncodingJob::sync_get(&db, video.id.clone())
+        .map_err(internal_error)?
+        .ok_or(Status::NotFound)?;
+
+    match video.status {
+        // the archived live stream stays watchable until the encoding is done
+        VideoStatus::Provisional { ref mut failed, .. } => *failed = None,
+        _ => video.status = VideoStatus::Processing,
+    }
+    video.sync_save(&db).map_err(internal_error)?;
+    encoding_queue::sync_enqueue(&db, video.id.clone(), job.timestamp).map_err(internal_error)?;
+
+    Ok(Json(video))
+}
+
+/// Output of the ffmpeg commands that produced a group of variants, e.g. `vp9_720p` or `h264`,
+/// depending on the encoding `mode`
+#[get("/videos/<id>/logs/<variant>")]
+pub fn encoding_log(
+    _admin: AdminToken,
+    db: State<Arc<RedisMultiplexed>>,
+    config: State<Arc<Config>>,
+    id: String,
+    variant: String,
+) -> Result<String, Status> {
+    let video = get_video(&db, id)?;
+
+    // only the names of the logs, anything else could point outside of the directory
+    let encoding_config = EncodingConfiguration::sync_load().map_err(internal_error)?;
+    if !encoding_config.log_names().contains(&variant) {
+        return Err(Status::NotFound);
+    }
+
+    let path = Path::new(&config.storage_dir)
+        .join("encoded")
+        .join(&video.id)
+        .join("logs")
+        .join(variant)
+        .with_extension("log");
+
+    std::fs::read_to_string(path).map_err(|_| Status::NotFound)
+}
+
+fn stream_keys_for(db: &RedisMultiplexed, id: &str) -> Result<Vec<StreamKey>, Status> {
+    Ok(StreamKey::sync_list(db)
+        .map_err(internal_error)?
+        .into_iter()
+        .map(|(_, stream_key)| stream_key)
+        .filter(|stream_key| stream_key.video.as_deref() == Some(id))
+        .collect())
+}
+
+#[get("/videos/<id>/stream_key")]
+pub fn stream_key(
+    _admin: AdminToken,
+    db: State<Arc<RedisMultiplexed>>,
+    id: String,
+) -> Result<Json<StreamKey>, Status> {
+    let video = get_video(&db, id)?;
+
+    stream_keys_for(&db, &video.id)?
+        .into_iter()
+        .nth(0)
+        .map(Json)
+        .ok_or(Status::NotFound)
+}
+
+/// Replaces every existing stream key of the video with a freshly generated one
+#[post("/videos/<id>/stream_key")]
+pub fn rotate_stream_key(
+    _admin: AdminToken,
+    db: State<Arc<RedisMultiplexed>>,
+    id: String,
+) -> Result<Json<StreamKey>, Status> {
+    let video = get_video(&db, id)?;
+
+    for old_key in stream_keys_for(&db, &video.id)? {
+        old_key.sync_del(&db).map_err(internal_error)?;
+    }
+
+    let stream_key = StreamKey::generate(Some(&video.id));
+    stream_key.sync_save(&db).map_err(internal_error)?;
+
+    info!("Rotated stream key for video `{}`", video.id);
+
+    Ok(Json(stream_key))
+}
+
+#[get("/channel_keys")]
+pub fn list_channel_keys(
+    _admin: AdminToken,
+    db: State<Arc<RedisMultiplexed>>,
+) -> Result<Json<Vec<StreamKey>>, Status> {
+    Ok(Json(
+        StreamKey::sync_list(&db)
+            .map_err(internal_error)?
+            .into_iter()
+            .map(|(_, stream_key)| stream_key)
+            .filter(|stream_key| stream_key.video.is_none())
+            .collect(),
+    ))
+}
+
+#[post("/channel_keys")]
+pub fn create_channel_key(
+    _admin: AdminToken,
+    db: State<Arc<RedisMultiplexed>>,
+) -> Result<Json<StreamKey>, Status> {
+    let stream_key = StreamKey::generate(None);
+    stream_key.sync_save(&db).map_err(internal_error)?;
+
+    Ok(Json(stream_key))
+}
+
+#[delete("/channel_keys/<key>")]
+pub fn delete_channel_key(
+    _admin: AdminToken,
+    db: State<Arc<RedisMultiplexed>>,
+    key: String,
+) -> Result<Status, Status> {
+    match StreamKey::sync_get(&db, key).map_err(internal_error)? {
+        Some(stream_key @ StreamKey { video: None, .. }) => {
+            stream_key.sync_del(&db).map_err(internal_error)?;
+
+            Ok(Status::NoContent)
+        }
+        _ => Err(Status::NotFound),
+    }
+}
+
+/// Lifts the ban of an IP, logged when a moderator bans a user
+#[delete("/bans/<ip>")]
+pub fn unban(
+    _admin: AdminToken,
+    db: State<Arc<RedisMultiplexed>>,
+    ip: String,
+) -> Result<Status, Status> {
+    let mut con = db.get_connection().map_err(internal_error)?;
+    if moderation::sync_unban(&mut con, &ip).map_err(internal_error)? {
+        Ok(Status::NoContent)
+    } else {
+        Err(Status::NotFound)
+    }
+}
+
+fn remove_video_files(storage_dir: &str, id: &str) -> std::io::Result<()> {
+    for segment in recording_segments(storage_dir, id)? {
+        std::fs::remove_file(segment)?;
+    }
+
+    let joined = joined_recording_path(storage_dir, id);
+    if joined.exists() {
+        std::fs::remove_file(joined)?;
+    }
+
+    let encoded = Path::new(storage_dir).join("encoded").join(id);
+    if encoded.exists() {
+        std::fs::remove_dir_all(encoded)?;
+    }
+
+    Ok(())
+}
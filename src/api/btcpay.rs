@@ -1,4 +1,5 @@
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use log::debug;
 
@@ -11,9 +12,11 @@ use rocket_contrib::json::Json;
 
 use btcpay::{Invoice, InvoiceStatus};
 
+use crate::chat;
+use crate::config::Config;
 use crate::db::{RedisEntity, RedisMultiplexed};
 use crate::tasks;
-use crate::types::{BoostMessageInvoice, MessageExtra, WsPacket};
+use crate::types::{BoostMessageInvoice, MessageExtra, Video, WsPacket};
 
 #[derive(Debug, Deserialize)]
 pub struct WebhookData {
@@ -28,7 +31,11 @@ pub struct WebhookEvent {
 }
 
 #[post("/btcpay_webhook", data = "<input>")]
-pub fn webhook(input: Json<WebhookData>, db: State<Arc<RedisMultiplexed>>) -> Status {
+pub fn webhook(
+    input: Json<WebhookData>,
+    db: State<Arc<RedisMultiplexed>>,
+    config: State<Arc<Config>>,
+) -> Status {
     // TODO: should check with the server
 
     match input.data.status {
@@ -52,18 +59,31 @@ pub fn webhook(input: Json<WebhookData>, db: State<Arc<RedisMultiplexed>>) -> St
                 // publish message
                 let extra = MessageExtra {
                     amount,
-                    timestamp: 0,
+                    // lets the clients joining later know how long the badge has left
+                    timestamp: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs(),
                     duration,
                 };
                 let packet = WsPacket::ServerMessage {
+                    id: chat::message_id(),
                     from: invoice.from,
                     message: invoice.message,
                     extra: Some(extra),
                 };
-                let _: () =
-                    redis::Cmd::publish(invoice.room, &serde_json::to_string(&packet).unwrap())
-                        .query(&mut db.get_connection().unwrap())
-                        .unwrap();
+                let offset = Video::sync_get(&db, invoice.room.clone())
+                    .unwrap()
+                    .map(|video| chat::offset(&video.status))
+                    .unwrap_or(0);
+                chat::sync_publish(
+                    &mut db.get_connection().unwrap(),
+                    &invoice.room,
+                    &packet,
+                    config.chat_history,
+                    offset,
+                )
+                .unwrap();
             }
         }
         _ => {}
@@ -0,0 +1,22 @@
+use std::sync::Arc;
+
+use rocket::http::Status;
+use rocket::{get, State};
+use rocket_contrib::json::Json;
+
+use crate::chat;
+use crate::db::{RedisEntity, RedisMultiplexed};
+use crate::types::{ChatLogEntry, Video};
+
+/// Every message sent to the chat of a video, with its offset from the start of the stream
+#[get("/videos/<id>/chat")]
+pub fn chat_log(
+    db: State<Arc<RedisMultiplexed>>,
+    id: String,
+) -> Result<Json<Vec<ChatLogEntry>>, Status> {
+    let video = Video::sync_get(&db, id).unwrap().ok_or(Status::NotFound)?;
+
+    Ok(Json(
+        chat::sync_log(&mut db.get_connection().unwrap(), &video.id).unwrap(),
+    ))
+}
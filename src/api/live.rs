@@ -0,0 +1,81 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use rocket::http::{ContentType, Status};
+use rocket::response::content::Content;
+use rocket::{get, State};
+
+use crate::config::Config;
+use crate::db::{RedisEntity, RedisMultiplexed};
+use crate::hls::{self, MediaPlaylist};
+use crate::types::{LiveRenditions, Video};
+
+fn m3u8(playlist: String) -> Content<String> {
+    Content(
+        ContentType::new("application", "vnd.apple.mpegurl"),
+        playlist,
+    )
+}
+
+/// HLS master playlist listing the renditions of a live stream that are actually available.
+///
+/// With `from_start` the media playlists cover the whole session, ignoring the DVR window
+#[get("/live/<id>/master.m3u8?<from_start>")]
+pub fn master_playlist(
+    db: State<Arc<RedisMultiplexed>>,
+    id: String,
+    from_start: Option<bool>,
+) -> Result<Content<String>, Status> {
+    let query = if from_start.unwrap_or(false) {
+        "?from_start=true"
+    } else {
+        ""
+    };
+
+    let live = LiveRenditions::sync_get(&db, id)
+        .unwrap()
+        .filter(|live| !live.renditions.is_empty())
+        .ok_or(Status::NotFound)?;
+
+    Ok(m3u8(hls::master_playlist(&live.renditions, |rendition| {
+        format!("renditions/{}.m3u8{}", rendition.name, query)
+    })))
+}
+
+/// Media playlist of a rendition, trimmed to the DVR window of the video.
+///
+/// nginx-rtmp keeps a much longer playlist, while the fragments themselves are still served by
+/// the CDN
+#[get("/live/<id>/renditions/<playlist>?<from_start>")]
+pub fn media_playlist(
+    db: State<Arc<RedisMultiplexed>>,
+    config: State<Arc<Config>>,
+    id: String,
+    playlist: String,
+    from_start: Option<bool>,
+) -> Result<Content<String>, Status> {
+    let video = Video::sync_get(&db, id).unwrap().ok_or(Status::NotFound)?;
+    let live = LiveRenditions::sync_get(&db, video.id.clone())
+        .unwrap()
+        .ok_or(Status::NotFound)?;
+
+    // only the names of the renditions are accepted, they end up in a path
+    let name = playlist.trim_end_matches(".m3u8");
+    if !live.renditions.iter().any(|r| r.name == name) {
+        return Err(Status::NotFound);
+    }
+
+    let path = Path::new(&config.hls_dir).join(name).join("index.m3u8");
+    let text = std::fs::read_to_string(path).map_err(|_| Status::NotFound)?;
+
+    let mut playlist =
+        MediaPlaylist::parse(&text).with_base_url(&format!("{}/hls/{}", config.cdn_url, name));
+    if from_start.unwrap_or(false) {
+        // segments are only ever appended, so players keep the beginning around
+        playlist.event = true;
+    } else {
+        playlist.trim(video.dvr_window.unwrap_or(config.dvr_window) as f32);
+    }
+
+    Ok(m3u8(playlist.render()))
+}
@@ -5,10 +5,15 @@ use rocket::routes;
 use rocket_contrib::templates::handlebars::handlebars_helper;
 use rocket_contrib::templates::Template;
 
+use tokio::runtime::Handle;
+
 use crate::config::Config;
 use crate::db::RedisMultiplexed;
 
+mod admin;
 mod btcpay;
+mod chat;
+mod live;
 mod pages;
 mod rtmp;
 
@@ -46,19 +51,44 @@ impl GlobalContext {
 
 handlebars_helper!(streq: |x: str, y: str| x == y);
 
-pub fn start(db: Arc<RedisMultiplexed>, config: Arc<Config>) {
+pub fn start(db: Arc<RedisMultiplexed>, config: Arc<Config>, runtime: Handle) {
     rocket::ignite()
         .manage(db)
+        .manage(runtime)
+        .manage(Arc::clone(&config))
         .manage(Arc::new(GlobalContext::new(config.deref())))
         .mount(
             "/",
             routes![
                 pages::index,
                 pages::watch,
+                live::master_playlist,
+                live::media_playlist,
+                chat::chat_log,
                 rtmp::callback_on_publish,
+                rtmp::callback_on_publish_done,
                 btcpay::webhook,
             ],
         )
+        .mount(
+            "/api",
+            routes![
+                admin::list_videos,
+                admin::video,
+                admin::create_video,
+                admin::edit_video,
+                admin::delete_video,
+                admin::upload_thumbnail,
+                admin::retry_encoding,
+                admin::encoding_log,
+                admin::stream_key,
+                admin::rotate_stream_key,
+                admin::list_channel_keys,
+                admin::create_channel_key,
+                admin::delete_channel_key,
+                admin::unban,
+            ],
+        )
         .attach(Template::custom(|engines| {
             engines.handlebars.register_helper("streq", Box::new(streq));
         }))
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use rocket::http::Status;
@@ -7,11 +8,25 @@ use rocket_contrib::templates::Template;
 
 use super::GlobalContext;
 use crate::db::{RedisEntity, RedisMultiplexed};
-use crate::types::{Video, VideoStatus};
+use crate::tasks::live_thumbnails::LIVE_THUMBNAIL;
+use crate::types::{LiveRenditions, LiveThumbnail, Video, VideoEncodingProgress, VideoStatus};
 
 #[get("/")]
 pub fn index(db: State<Arc<RedisMultiplexed>>) -> FullResponse {
-    let context = Video::sync_list(&db).unwrap();
+    let live_thumbnails = LiveThumbnail::sync_list(&db).unwrap();
+    let context = Video::sync_list(&db)
+        .unwrap()
+        .into_iter()
+        .map(|(id, video)| {
+            let mut video = serde_json::to_value(video).unwrap();
+            if let Some(thumbnail) = live_thumbnails.get(&id) {
+                video["live_thumbnail"] =
+                    format!("{}?t={}", LIVE_THUMBNAIL, thumbnail.timestamp).into();
+            }
+
+            (id, video)
+        })
+        .collect::<HashMap<_, _>>();
     Template::render("index", &context).into()
 }
 
@@ -29,7 +44,17 @@ pub fn watch(
                 status: VideoStatus::Live { .. },
                 ..
             },
-        ) => Template::render("watch-live", &globals.extend(&v)).into(),
+        ) => {
+            let mut context = globals.extend(&v);
+            // the player menu only lists the renditions that are actually being transcoded
+            context["qualities"] = LiveRenditions::sync_get(&db, v.id)
+                .unwrap()
+                .map(|live| live.renditions.iter().map(|r| r.height).collect())
+                .unwrap_or_else(Vec::new)
+                .into();
+
+            Template::render("watch-live", &context).into()
+        }
         Some(
             v
             @
@@ -37,7 +62,36 @@ pub fn watch(
                 status: VideoStatus::Processing,
                 ..
             },
+        ) => {
+            let mut context = globals.extend(&v);
+            context["progress"] =
+                serde_json::to_value(VideoEncodingProgress::sync_get(&db, v.id).unwrap()).unwrap();
+
+            Template::render("watch-live", &context).into()
+        }
+        Some(
+            v
+            @
+            Video {
+                status: VideoStatus::Failed { .. },
+                ..
+            },
         ) => Template::render("watch-live", &globals.extend(&v)).into(),
+        Some(
+            v
+            @
+            Video {
+                status: VideoStatus::Provisional { .. },
+                ..
+            },
+        ) => {
+            let mut context = globals.extend(&v);
+            context["adaptive"] = context["status"]["Provisional"]["hls"].clone();
+            context["progress"] =
+                serde_json::to_value(VideoEncodingProgress::sync_get(&db, v.id).unwrap()).unwrap();
+
+            Template::render("watch-published", &context).into()
+        }
         Some(
             v
             @
@@ -45,7 +99,12 @@ pub fn watch(
                 status: VideoStatus::Published { .. },
                 ..
             },
-        ) => Template::render("watch-published", &globals.extend(&v)).into(),
+        ) => {
+            let mut context = globals.extend(&v);
+            context["adaptive"] = context["status"]["Published"]["hls"].clone();
+
+            Template::render("watch-published", &context).into()
+        }
         Some(
             v
             @
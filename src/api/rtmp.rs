@@ -1,12 +1,21 @@
 use std::sync::Arc;
 
+use log::info;
+
 use rocket::http::Status;
 use rocket::request::{FromForm, LenientForm};
+use rocket::response::Response;
 use rocket::{post, State};
 
-use crate::db::RedisMultiplexed;
+use tokio::runtime::Handle;
+
+use crate::config::Config;
+use crate::db::{RedisEntity, RedisMultiplexed};
 use crate::tasks;
-use crate::types::{Video, VideoStatus};
+use crate::types::StreamKey;
+
+/// Stream names starting with this prefix, followed by a channel key, go live on a new video
+pub const GO_LIVE_PREFIX: &str = "golive_";
 
 #[derive(Debug, FromForm)]
 pub struct OnPublishForm {
@@ -19,12 +28,80 @@ pub struct OnPublishForm {
     name: String,
 }
 
+#[derive(Debug, FromForm)]
+pub struct OnPublishDoneForm {
+    app: String,
+    addr: String,
+    call: String,
+    name: String,
+}
+
+/// nginx-rtmp renames the stream to the `Location` of a 3xx answer to `on_publish`
+fn rename_stream(name: String) -> Response<'static> {
+    Response::build()
+        .status(Status::Found)
+        .raw_header("Location", name)
+        .finalize()
+}
+
 #[post("/callback/on_publish", data = "<on_publish>")]
 pub fn callback_on_publish(
     on_publish: LenientForm<OnPublishForm>,
     db: State<Arc<RedisMultiplexed>>,
+    config: State<Arc<Config>>,
+) -> Result<Response<'static>, Status> {
+    let (key, go_live) = if on_publish.name.starts_with(GO_LIVE_PREFIX) {
+        (&on_publish.name[GO_LIVE_PREFIX.len()..], true)
+    } else {
+        (on_publish.name.as_str(), false)
+    };
+
+    match (StreamKey::sync_get(&db, key.to_string()).unwrap(), go_live) {
+        (Some(mut stream_key @ StreamKey { video: None, .. }), true) => {
+            let resumed = stream_key.live_video.clone().filter(|id| {
+                tasks::live_monitor::publish_stream(Arc::clone(db.inner()), &config, id.clone())
+                    == Status::Ok
+            });
+
+            let id = match resumed {
+                Some(id) => id,
+                None => {
+                    let video = tasks::live_monitor::allocate_stream(Arc::clone(db.inner()));
+                    stream_key.live_video = Some(video.id.clone());
+                    stream_key.sync_save(&db).unwrap();
+
+                    video.id
+                }
+            };
+
+            Ok(rename_stream(id))
+        }
+        (Some(StreamKey { video: Some(id), .. }), false) => {
+            match tasks::live_monitor::publish_stream(Arc::clone(db.inner()), &config, id.clone()) {
+                Status::Ok => Ok(rename_stream(id)),
+                status => Err(status),
+            }
+        }
+        _ => {
+            info!("Rejected stream with invalid key from {}", on_publish.addr);
+            Err(Status::Forbidden)
+        }
+    }
+}
+
+#[post("/callback/on_publish_done", data = "<on_publish_done>")]
+pub fn callback_on_publish_done(
+    on_publish_done: LenientForm<OnPublishDoneForm>,
+    db: State<Arc<RedisMultiplexed>>,
+    config: State<Arc<Config>>,
+    runtime: State<Handle>,
 ) -> Status {
-    // TODO: special name to allocate a new id and redirect to it
+    // the stream has already been renamed to the video id by `callback_on_publish`
+    runtime.spawn(tasks::live_monitor::disconnect_stream(
+        Arc::clone(db.inner()),
+        Arc::clone(config.inner()),
+        on_publish_done.name.clone(),
+    ));
 
-    tasks::live_monitor::publish_stream(Arc::clone(db.inner()), on_publish.name.clone())
+    Status::Ok
 }
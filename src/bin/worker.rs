@@ -0,0 +1,32 @@
+//! Standalone encoding worker, pulling jobs from the same Redis queue as the main server.
+//!
+//! Every worker needs a unique id, which must stay the same across restarts so that the worker
+//! can pick up again the job it was running when it was stopped:
+//!
+//! ```text
+//! cargo run --bin selfstream-worker -- worker-1
+//! ```
+
+use std::env;
+use std::sync::Arc;
+
+use selfstream::{config, db, tasks};
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    let worker = env::args()
+        .nth(1)
+        .expect("Usage: selfstream-worker <worker id>");
+
+    let config = config::Config::new().await.unwrap();
+    let config = Arc::new(config);
+
+    let db = db::RedisMultiplexed::new(config.redis_server.as_str()).unwrap();
+    let db = Arc::new(db);
+
+    tasks::encoding_queue::run_worker(db, config, worker)
+        .await
+        .expect("Worker stopped");
+}
@@ -0,0 +1,210 @@
+//! Chat messages are published on the room channel and also appended to a capped Redis stream,
+//! so that the latest ones can be sent to whoever joins later. Every message is also kept in the
+//! log of the video, to replay the chat along the recording once the stream is over.
+//!
+//! Messages deleted by the moderators stay in both, and are filtered out when reading them
+//!
+//! The log isn't capped like the history, as the replay needs all of it: it's only as long as the
+//! rate limits allow for the duration of the stream, and it's removed along with the video
+
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+use redis::aio::MultiplexedConnection;
+use redis::{Connection, Pipeline, RedisResult};
+
+use crate::types::{ChatLogEntry, VideoStatus, WsPacket};
+
+/// Field of the stream entries containing the serialized packet
+const PACKET_FIELD: &str = "packet";
+
+fn history_key(room: &str) -> String {
+    format!("chat:{}", room)
+}
+
+fn log_key(room: &str) -> String {
+    format!("chat_log:{}", room)
+}
+
+fn deleted_key(room: &str) -> String {
+    format!("chat_deleted:{}", room)
+}
+
+pub fn message_id() -> String {
+    thread_rng().sample_iter(&Alphanumeric).take(11).collect()
+}
+
+/// Offset in the recording of a message sent now, which leaves out the time the streamer spent
+/// disconnected
+pub fn offset(status: &VideoStatus) -> u64 {
+    match status {
+        VideoStatus::Live {
+            started_timestamp,
+            disconnected_timestamp,
+            disconnected_seconds,
+            ..
+        } => {
+            // while disconnected, the messages are placed where the recording will resume
+            let now = disconnected_timestamp.unwrap_or_else(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs()
+            });
+
+            now.saturating_sub(*started_timestamp)
+                .saturating_sub(*disconnected_seconds)
+        }
+        _ => 0,
+    }
+}
+
+fn publish_pipeline(room: &str, packet: &WsPacket, history: usize, offset: u64) -> Pipeline {
+    let serialized = serde_json::to_string(packet).unwrap();
+
+    let mut pipe = redis::pipe();
+    pipe.atomic()
+        .cmd("XADD")
+        .arg(history_key(room))
+        // trimming with `~` is much cheaper, and keeps at least `history` entries anyway
+        .arg("MAXLEN")
+        .arg("~")
+        .arg(history)
+        .arg("*")
+        .arg(PACKET_FIELD)
+        .arg(&serialized)
+        .ignore()
+        .cmd("PUBLISH")
+        .arg(room)
+        .arg(&serialized)
+        .ignore();
+
+    if let WsPacket::ServerMessage {
+        id,
+        from,
+        message,
+        extra,
+    } = packet
+    {
+        let entry = ChatLogEntry {
+            id: id.clone(),
+            offset,
+            from: from.clone(),
+            message: message.clone(),
+            extra: extra.clone(),
+        };
+        pipe.cmd("RPUSH")
+            .arg(log_key(room))
+            .arg(serde_json::to_string(&entry).unwrap())
+            .ignore();
+    }
+
+    pipe
+}
+
+/// Sends a message to everyone in the room, keeping the last `history` ones
+pub async fn publish(
+    con: &mut MultiplexedConnection,
+    room: &str,
+    packet: &WsPacket,
+    history: usize,
+    offset: u64,
+) -> RedisResult<()> {
+    publish_pipeline(room, packet, history, offset)
+        .query_async(con)
+        .await
+}
+
+pub fn sync_publish(
+    con: &mut Connection,
+    room: &str,
+    packet: &WsPacket,
+    history: usize,
+    offset: u64,
+) -> RedisResult<()> {
+    publish_pipeline(room, packet, history, offset).query(con)
+}
+
+/// Last `count` messages sent to the room, from the oldest
+pub async fn history(
+    con: &mut MultiplexedConnection,
+    room: &str,
+    count: usize,
+) -> RedisResult<Vec<WsPacket>> {
+    // every entry is an id followed by the list of fields and values
+    let (entries, deleted): (Vec<(String, Vec<String>)>, HashSet<String>) = redis::pipe()
+        .cmd("XREVRANGE")
+        .arg(history_key(room))
+        .arg("+")
+        .arg("-")
+        .arg("COUNT")
+        .arg(count)
+        .cmd("SMEMBERS")
+        .arg(deleted_key(room))
+        .query_async(con)
+        .await?;
+
+    Ok(entries
+        .into_iter()
+        .rev()
+        .filter_map(|(_, fields)| {
+            let value = fields
+                .chunks(2)
+                .find(|pair| pair[0] == PACKET_FIELD)?
+                .get(1)?;
+            serde_json::from_str(value).ok()
+        })
+        .filter(|packet| match packet {
+            WsPacket::ServerMessage { id, .. } => !deleted.contains(id),
+            _ => true,
+        })
+        .collect())
+}
+
+/// Hides a message from the history and the log, and tells everyone in the room to remove it
+pub async fn delete(con: &mut MultiplexedConnection, room: &str, id: &str) -> RedisResult<()> {
+    let packet = WsPacket::MessageDeleted { id: id.to_string() };
+
+    redis::pipe()
+        .atomic()
+        .cmd("SADD")
+        .arg(deleted_key(room))
+        .arg(id)
+        .ignore()
+        .cmd("PUBLISH")
+        .arg(room)
+        .arg(serde_json::to_string(&packet).unwrap())
+        .ignore()
+        .query_async(con)
+        .await
+}
+
+/// Every message sent to the room, from the oldest
+pub fn sync_log(con: &mut Connection, room: &str) -> RedisResult<Vec<ChatLogEntry>> {
+    let (entries, deleted): (Vec<String>, HashSet<String>) = redis::pipe()
+        .cmd("LRANGE")
+        .arg(log_key(room))
+        .arg(0)
+        .arg(-1)
+        .cmd("SMEMBERS")
+        .arg(deleted_key(room))
+        .query(con)?;
+
+    Ok(entries
+        .iter()
+        .filter_map(|entry| serde_json::from_str::<ChatLogEntry>(entry).ok())
+        .filter(|entry| !deleted.contains(&entry.id))
+        .collect())
+}
+
+/// Removes the history, the log and the deleted messages of the room
+pub fn sync_delete(con: &mut Connection, room: &str) -> RedisResult<()> {
+    redis::cmd("DEL")
+        .arg(history_key(room))
+        .arg(log_key(room))
+        .arg(deleted_key(room))
+        .query(con)
+}
@@ -1,8 +1,14 @@
+use std::collections::HashMap;
+
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 
 use serde::{Deserialize, Serialize};
 
+/// Seconds of the live streams nginx keeps in the playlists, its `hls_playlist_length`: the DVR
+/// window can't be any longer
+pub const MAX_DVR_WINDOW: u64 = 12 * 60 * 60;
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
     pub listen: String,
@@ -15,9 +21,85 @@ pub struct Config {
 
     pub stat_url: String,
 
+    /// Token of the admin API, which is disabled when it's empty
+    #[serde(default)]
+    pub admin_token: String,
+
+    /// Seconds a disconnected streamer has to publish again and resume the same video
+    #[serde(default = "default_reconnect_grace")]
+    pub reconnect_grace: u64,
+
+    /// RTMP server the live transcoders read from and publish to
+    #[serde(default = "default_rtmp_url")]
+    pub rtmp_url: String,
+
+    /// Directory nginx-rtmp writes the live HLS fragments to
+    #[serde(default = "default_hls_dir")]
+    pub hls_dir: String,
+
+    /// Seconds between the frames captured as thumbnails of the live streams, 0 to disable them
+    #[serde(default = "default_live_thumbnail_interval")]
+    pub live_thumbnail_interval: u64,
+
+    /// Seconds of a live stream that can be rewound, unless it's set on the video
+    #[serde(default = "default_dvr_window")]
+    pub dvr_window: u64,
+
+    /// Chat messages kept for the viewers joining later
+    #[serde(default = "default_chat_history")]
+    pub chat_history: usize,
+
+    /// Whether the server should also encode videos, or leave them to `selfstream-worker`s
+    #[serde(default = "default_local_worker")]
+    pub local_worker: bool,
+
+    /// Names of the chat moderators, with the token they join with
+    #[serde(default)]
+    pub moderators: HashMap<String, String>,
+
+    /// Seconds the IPs banned by the moderators are kept out of the chat
+    #[serde(default = "default_ban_duration")]
+    pub ban_duration: u64,
+
+    /// How often every chat user can send messages and request invoices
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+
     pub btcpay: BTCPayConfig,
 }
 
+fn default_reconnect_grace() -> u64 {
+    30
+}
+
+fn default_rtmp_url() -> String {
+    "rtmp://localhost:1935".into()
+}
+
+fn default_hls_dir() -> String {
+    "/tmp/hls".into()
+}
+
+fn default_live_thumbnail_interval() -> u64 {
+    30
+}
+
+fn default_dvr_window() -> u64 {
+    2 * 60 * 60
+}
+
+fn default_chat_history() -> usize {
+    50
+}
+
+fn default_local_worker() -> bool {
+    true
+}
+
+fn default_ban_duration() -> u64 {
+    7 * 24 * 60 * 60
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct BTCPayConfig {
     pub key: String,
@@ -26,6 +108,59 @@ pub struct BTCPayConfig {
     pub webhook: String,
 }
 
+/// A token bucket allowing `burst` requests at once, refilled at `per_minute`
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct RequestLimits {
+    /// For every websocket connection
+    pub connection: RateLimit,
+    /// Shared between all the connections from the same IP
+    pub ip: RateLimit,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RateLimits {
+    pub messages: RequestLimits,
+    /// Every invoice is actually created on BTCPay, so they are much more limited than messages
+    pub invoices: RequestLimits,
+    /// Requests over the limits in a minute before the connection is closed
+    pub max_violations: u32,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            messages: RequestLimits {
+                connection: RateLimit {
+                    burst: 5,
+                    per_minute: 30,
+                },
+                ip: RateLimit {
+                    burst: 20,
+                    per_minute: 120,
+                },
+            },
+            invoices: RequestLimits {
+                connection: RateLimit {
+                    burst: 2,
+                    per_minute: 4,
+                },
+                ip: RateLimit {
+                    burst: 5,
+                    per_minute: 10,
+                },
+            },
+            max_violations: 10,
+        }
+    }
+}
+
 impl Config {
     pub async fn new() -> Result<Self, ConfigError> {
         let mut contents = vec![];
@@ -33,7 +168,14 @@ impl Config {
         let mut config_file = File::open("config.toml").await?;
         config_file.read_to_end(&mut contents).await?;
 
-        Ok(toml::from_slice(&contents)?)
+        let config: Config = toml::from_slice(&contents)?;
+        if config.dvr_window > MAX_DVR_WINDOW {
+            return Err(ConfigError::Invalid(
+                "`dvr_window` is longer than the `hls_playlist_length` of nginx",
+            ));
+        }
+
+        Ok(config)
     }
 }
 
@@ -41,6 +183,7 @@ impl Config {
 pub enum ConfigError {
     TokioIO(tokio::io::Error),
     TOML(toml::de::Error),
+    Invalid(&'static str),
 }
 
 impl From<tokio::io::Error> for ConfigError {
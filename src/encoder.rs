@@ -1,6 +1,8 @@
 use std::collections::HashMap;
 use std::env;
 use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Instant;
 
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
@@ -9,13 +11,18 @@ use serde::Deserialize;
 
 use log::{debug, info, trace};
 
+use futures::channel::mpsc::UnboundedSender;
+use futures::future::join;
+use futures::stream::{self, StreamExt};
+
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
 use tokio::process::Command;
 
 use crate::config::Config;
 use crate::monitor::NginxMeta;
-use crate::probe::VideoProbe;
+use crate::probe::{VideoProbe, VideoProbeError};
+use crate::types::EncodingProgress;
 
 #[derive(Debug)]
 pub struct Encoder {
@@ -30,25 +37,89 @@ pub struct Encoder {
     duration: f32,
 
     global_config: Config,
+    progress: Option<UnboundedSender<EncodingProgress>>,
 }
 
 #[derive(Debug)]
 pub struct EncoderResult {
     pub variants: Vec<(usize, String, String)>,
     pub duration: f32,
+    /// HLS master playlist, relative to the output directory
+    pub hls: Option<String>,
+    /// DASH manifest, relative to the output directory
+    pub dash: Option<String>,
+    /// Poster frame, relative to the output directory
+    pub poster: String,
+    /// Candidate thumbnails, relative to the output directory
+    pub thumbnails: Vec<String>,
+    /// WebVTT index of the seek preview sprites, relative to the output directory
+    pub trickplay: String,
 }
 
-impl Encoder {
-    pub async fn new(id: String, global_config: &Config) -> Result<Self, EncoderError> {
-        let mut contents = vec![];
+/// Lists the recording segments of a video in chronological order.
+///
+/// nginx-rtmp appends the start timestamp to every segment when `record_unique` is on, while
+/// older recordings are stored as a plain `<id>.flv`
+pub fn recording_segments(storage_dir: &str, id: &str) -> std::io::Result<Vec<PathBuf>> {
+    let recordings_dir = Path::new(storage_dir).join("recordings");
+    if !recordings_dir.exists() {
+        return Ok(vec![]);
+    }
 
-        let mut config_file = File::open("encoding.toml").await?;
-        config_file.read_to_end(&mut contents).await?;
+    let mut segments = Vec::new();
+    for entry in std::fs::read_dir(recordings_dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("flv") {
+            continue;
+        }
+
+        let stem = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(stem) => stem,
+            None => continue,
+        };
+        let timestamp = if stem == id {
+            0
+        } else if stem.starts_with(id) && stem[id.len()..].starts_with('-') {
+            match stem[id.len() + 1..].parse::<u64>() {
+                Ok(timestamp) => timestamp,
+                Err(_) => continue,
+            }
+        } else {
+            continue;
+        };
+
+        segments.push((timestamp, path));
+    }
+    segments.sort();
+
+    Ok(segments.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Path of the file containing every segment of a recording, joined together
+pub fn joined_recording_path(storage_dir: &str, id: &str) -> PathBuf {
+    Path::new(storage_dir)
+        .join("recordings")
+        .join(format!("{}.joined.flv", id))
+}
+
+impl Encoder {
+    pub async fn new(
+        id: String,
+        segments: Vec<PathBuf>,
+        global_config: &Config,
+    ) -> Result<Self, EncoderError> {
+        let path = match segments.len() {
+            0 => return Err(EncoderError::MissingInput),
+            1 => segments[0].clone(),
+            _ => {
+                let path = joined_recording_path(&global_config.storage_dir, &id);
+                if !path.exists() {
+                    concat_segments(&segments, &path).await?;
+                }
 
-        let path = Path::new(&global_config.storage_dir)
-            .join("recordings")
-            .join(&id)
-            .with_extension("flv");
+                path
+            }
+        };
         if !path.exists() {
             return Err(EncoderError::MissingInput);
         }
@@ -66,7 +137,7 @@ impl Encoder {
                 _ => None,
             })
             .nth(0)
-            .unwrap();
+            .ok_or(EncoderError::VideoProbe(VideoProbeError::MalformedOutput))?;
         let duration = meta
             .iter()
             .filter_map(|a| match a {
@@ -74,9 +145,9 @@ impl Encoder {
                 _ => None,
             })
             .nth(0)
-            .unwrap();
+            .ok_or(EncoderError::VideoProbe(VideoProbeError::MalformedOutput))?;
 
-        let config = toml::from_slice(&contents)?;
+        let config = EncodingConfiguration::load().await?;
         Ok(Encoder {
             id,
             config,
@@ -89,226 +160,753 @@ impl Encoder {
             duration,
 
             global_config: global_config.clone(),
+            progress: None,
         })
     }
 
-    pub async fn encode(self) -> Result<EncoderResult, EncoderError> {
-        info!("Encoding video `{}`", self.id);
+    /// Reports the progress of every ffmpeg run on `progress`
+    pub fn with_progress(mut self, progress: UnboundedSender<EncodingProgress>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
 
-        let mut variants = Vec::new();
+    /// Produces a group of variants with a single set of ffmpeg invocations, logging their output
+    /// to `logs/<name>.log`.
+    ///
+    /// Every variant is written to its `partial` file first, which is moved to `output` only once
+    /// all the commands succeed
+    async fn run_group(&self, name: &str, outputs: &[VariantOutput]) -> Result<(), EncoderError> {
+        let mut workdir = env::temp_dir();
+        let rand_string: String = thread_rng().sample_iter(&Alphanumeric).take(30).collect();
+        workdir.push(rand_string);
+        tokio::fs::create_dir_all(&workdir).await?;
 
-        let output_dir = Path::new(&self.global_config.storage_dir)
-            .join("encoded")
-            .join(&self.id);
-        if !output_dir.exists() {
-            tokio::fs::create_dir_all(&output_dir).await?;
+        debug!(
+            "{} ({}): {} -> {:?}",
+            name,
+            workdir.display(),
+            self.path.display(),
+            outputs.iter().map(|o| &o.output).collect::<Vec<_>>()
+        );
+
+        let commands = commands(outputs, 48000, &self.path, &workdir);
+        let result = self.run_commands(name, commands).await;
+
+        for output in outputs {
+            if output.partial.exists() && result.is_err() {
+                tokio::fs::remove_file(&output.partial).await?;
+            }
+        }
+        tokio::fs::remove_dir_all(&workdir).await?;
+
+        result?;
+        for output in outputs {
+            tokio::fs::rename(&output.partial, &output.output).await?;
         }
 
-        for (id, vp9_config) in self.config.vod.vp9 {
-            if self.video_height < vp9_config.height {
+        Ok(())
+    }
+
+    /// Lists the variants of `C` that fit the source in `variants`, and the ones that still have
+    /// to be encoded in `pending`
+    fn plan<C: Codec>(
+        &self,
+        configs: &HashMap<String, EncodingVariant>,
+        output_dir: &Path,
+        variants: &mut Vec<(usize, String, String)>,
+        pending: &mut Vec<VariantOutput>,
+    ) {
+        for (id, variant_config) in configs {
+            if self.video_height < variant_config.height {
                 continue;
             }
 
-            let fps = min(
-                self.video_framerate,
-                vp9_config.max_fps.unwrap_or(self.video_framerate),
-            );
-
-            let filename = format!("vp9_{}.webm", id);
-            variants.push((vp9_config.height, "video/webm".into(), filename.clone()));
+            let name = format!("{}_{}", C::NAME, id);
+            let filename = format!("{}.{}", name, C::EXTENSION);
+            variants.push((variant_config.height, C::MIME.into(), filename.clone()));
             let output = output_dir.join(filename);
             if output.exists() {
-                debug!("VP9 {}. Skipping", id);
+                debug!("{}. Skipping", name);
                 continue;
             }
 
-            let mut workdir = env::temp_dir();
-            let rand_string: String = thread_rng().sample_iter(&Alphanumeric).take(30).collect();
-            workdir.push(rand_string);
-            tokio::fs::create_dir_all(&workdir).await?;
-
-            debug!(
-                "VP9 {} ({}): {} -> {}",
-                id,
-                workdir.display(),
-                self.path.display(),
-                output.display()
-            );
-            for mut cmd in VP9::commands(
-                vp9_config.bitrate,
-                vp9_config.height,
-                fps,
-                vp9_config.audio_channels,
-                48000,
-                &self.path,
-                &output,
-                &workdir,
-            )? {
-                trace!("{:?}", cmd);
-
-                let _output = cmd.output().await?;
-            }
+            pending.push(VariantOutput {
+                name,
+                codec: C::NAME,
+                passes: C::PASSES,
+                output_args: C::output_args,
+                threads: self.config.vod.threads,
 
-            tokio::fs::remove_dir_all(&workdir).await?;
+                height: variant_config.height,
+                fps: variant_config
+                    .max_fps
+                    .filter(|max_fps| *max_fps < self.video_framerate),
+                bitrate: variant_config.bitrate,
+                audio_channels: variant_config.audio_channels,
+
+                partial: output.with_extension("partial"),
+                output,
+            });
         }
+    }
 
-        for (id, h264_config) in self.config.vod.h264 {
-            if self.video_height < h264_config.height {
-                continue;
-            }
+    async fn run_commands(
+        &self,
+        variant: &str,
+        commands: Vec<Command>,
+    ) -> Result<(), EncoderError> {
+        let logs_dir = Path::new(&self.global_config.storage_dir)
+            .join("encoded")
+            .join(&self.id)
+            .join("logs");
+        if !logs_dir.exists() {
+            tokio::fs::create_dir_all(&logs_dir).await?;
+        }
+        let log_path = logs_dir.join(variant).with_extension("log");
 
-            let fps = min(
-                self.video_framerate,
-                h264_config.max_fps.unwrap_or(self.video_framerate),
-            );
+        let mut log = String::new();
+        let passes = commands.len();
 
-            let filename = format!("h264_{}.mp4", id);
-            variants.push((h264_config.height, "video/mp4".into(), filename.clone()));
-            let output = output_dir.join(filename);
-            if output.exists() {
-                debug!("H.264 {}. Skipping", id);
-                continue;
+        for (pass, mut cmd) in commands.into_iter().enumerate() {
+            trace!("{:?}", cmd);
+
+            let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+            let stdout = child.stdout.take().unwrap();
+            let mut stderr = child.stderr.take().unwrap();
+
+            let read_progress = async {
+                let mut lines = BufReader::new(stdout).lines();
+
+                let started = Instant::now();
+                let mut position = 0.0;
+                while let Some(line) = lines.next_line().await? {
+                    if let Some(out_time) = parse_out_time(&line) {
+                        position = out_time;
+                    } else if line.starts_with("progress=") {
+                        self.report_progress(variant, pass + 1, passes, position, started);
+                    }
+                }
+
+                Ok::<(), tokio::io::Error>(())
+            };
+            // stderr has to be drained at the same time, or ffmpeg would block once the pipe is full
+            let read_stderr = async {
+                let mut buf = Vec::new();
+                stderr.read_to_end(&mut buf).await.map(|_| buf)
+            };
+
+            let (progress_result, stderr_result) = join(read_progress, read_stderr).await;
+            progress_result?;
+            let stderr = String::from_utf8_lossy(&stderr_result?).into_owned();
+            let status = child.await?;
+
+            log.push_str(&format!("$ {:?}\n{}\n", cmd, stderr));
+            tokio::fs::write(&log_path, &log).await?;
+
+            if !status.success() {
+                return Err(EncoderError::CommandFailed {
+                    code: status.code(),
+                    stderr: tail(&stderr, 20),
+                });
             }
+        }
+
+        Ok(())
+    }
+
+    fn report_progress(
+        &self,
+        variant: &str,
+        pass: usize,
+        passes: usize,
+        position: f32,
+        started: Instant,
+    ) {
+        let progress = match &self.progress {
+            Some(progress) => progress,
+            None => return,
+        };
+
+        let fraction = min(position / self.duration, 1.0);
+        let eta = match fraction {
+            f if f > 0.0 => Some((started.elapsed().as_secs_f32() * (1.0 - f) / f) as u64),
+            _ => None,
+        };
+
+        // the receiver going away shouldn't interrupt the encoding
+        let _ = progress.unbounded_send(EncodingProgress {
+            id: self.id.clone(),
+            variant: variant.to_string(),
+            pass,
+            passes,
+            progress: fraction,
+            eta,
+        });
+    }
+
+    pub async fn encode(self) -> Result<EncoderResult, EncoderError> {
+        info!("Encoding video `{}`", self.id);
+
+        let mut variants = Vec::new();
+
+        let output_dir = Path::new(&self.global_config.storage_dir)
+            .join("encoded")
+            .join(&self.id);
+        if !output_dir.exists() {
+            tokio::fs::create_dir_all(&output_dir).await?;
+        }
 
-            let mut workdir = env::temp_dir();
-            let rand_string: String = thread_rng().sample_iter(&Alphanumeric).take(30).collect();
-            workdir.push(rand_string);
-            tokio::fs::create_dir_all(&workdir).await?;
-
-            debug!(
-                "H.264 {} ({}): {} -> {}",
-                id,
-                workdir.display(),
-                self.path.display(),
-                output.display()
-            );
-            for mut cmd in H264::commands(
-                h264_config.bitrate,
-                h264_config.height,
-                fps,
-                h264_config.audio_channels,
-                48000,
-                &self.path,
-                &output,
-                &workdir,
-            )? {
-                trace!("{:?}", cmd);
-
-                let _output = cmd.output().await?;
+        let mut pending = Vec::new();
+        self.plan::<VP9>(
+            &self.config.vod.vp9,
+            &output_dir,
+            &mut variants,
+            &mut pending,
+        );
+        self.plan::<H264>(
+            &self.config.vod.h264,
+            &output_dir,
+            &mut variants,
+            &mut pending,
+        );
+
+        let mut groups: Vec<(String, Vec<VariantOutput>)> = Vec::new();
+        for output in pending {
+            let group_name = match self.config.vod.mode {
+                EncodingMode::Separate => output.name.clone(),
+                EncodingMode::PerCodec => output.codec.to_string(),
+                EncodingMode::Single => "all".to_string(),
+            };
+
+            match groups.iter_mut().find(|(name, _)| name == &group_name) {
+                Some((_, group)) => group.push(output),
+                None => groups.push((group_name, vec![output])),
             }
+        }
 
-            tokio::fs::remove_dir_all(&workdir).await?;
+        // groups write to different files, so they can run in any order
+        let encoder = &self;
+        let results = stream::iter(groups)
+            .map(|(name, outputs)| async move { encoder.run_group(&name, &outputs).await })
+            .buffer_unordered(self.config.vod.concurrency.max(1))
+            .collect::<Vec<_>>()
+            .await;
+        for result in results {
+            result?;
         }
 
+        let packaged = self.package(&output_dir, &variants).await?;
+        let thumbnails = self.thumbnails(&output_dir).await?;
+        let trickplay = self.trickplay(&output_dir).await?;
+
         Ok(EncoderResult {
             variants,
             duration: self.duration,
+            hls: Some(format!("{}/{}", ADAPTIVE_DIR, HLS_MASTER)).filter(|_| packaged),
+            dash: Some(format!("{}/{}", ADAPTIVE_DIR, DASH_MANIFEST)).filter(|_| packaged),
+            poster: POSTER.to_string(),
+            thumbnails,
+            trickplay,
+        })
+    }
+
+    /// Renders a frame every `TRICKPLAY_INTERVAL` seconds in grids of thumbnails, and writes the
+    /// WebVTT file telling the player which region of which sprite to show for every time range
+    async fn trickplay(&self, output_dir: &Path) -> Result<String, EncoderError> {
+        let vtt = format!("{}/{}", TRICKPLAY_DIR, TRICKPLAY_VTT);
+        let trickplay_dir = output_dir.join(TRICKPLAY_DIR);
+        if trickplay_dir.join(TRICKPLAY_VTT).exists() {
+            debug!("{}. Skipping", TRICKPLAY_DIR);
+            return Ok(vtt);
+        }
+
+        let partial = trickplay_dir.with_extension("partial");
+        if partial.exists() {
+            tokio::fs::remove_dir_all(&partial).await?;
+        }
+        tokio::fs::create_dir_all(&partial).await?;
+
+        // every tile must have the same size, the WebVTT file addresses them by pixel
+        let width = TRICKPLAY_WIDTH;
+        let height = (width * self.video_height / self.video_width.max(1) + 1) / 2 * 2;
+
+        let mut cmd = Command::new("ffmpeg");
+        cmd.current_dir(&partial)
+            .kill_on_drop(true)
+            .arg("-y")
+            .args(&["-progress", "pipe:1"])
+            .arg("-nostats")
+            .args(&["-i", self.path.to_str().unwrap()])
+            .args(&[
+                "-vf",
+                &format!(
+                    "fps=1/{},scale={}:{},tile={}x{}",
+                    TRICKPLAY_INTERVAL, width, height, TRICKPLAY_COLUMNS, TRICKPLAY_ROWS
+                ),
+            ])
+            .arg("-an")
+            .args(&["-q:v", "5"])
+            .arg("sprite_%03d.jpg");
+
+        let result = self.run_commands(TRICKPLAY_DIR, vec![cmd]).await;
+        if result.is_err() {
+            tokio::fs::remove_dir_all(&partial).await?;
+        }
+        result?;
+
+        tokio::fs::write(
+            partial.join(TRICKPLAY_VTT),
+            trickplay_vtt(self.duration, width, height),
+        )
+        .await?;
+        tokio::fs::rename(&partial, &trickplay_dir).await?;
+
+        Ok(vtt)
+    }
+
+    /// Extracts a large poster frame and a few small thumbnails taken at regular intervals
+    async fn thumbnails(&self, output_dir: &Path) -> Result<Vec<String>, EncoderError> {
+        let thumbnails = (1..=THUMBNAIL_CANDIDATES)
+            .map(|i| format!("thumb_{}.jpg", i))
+            .collect::<Vec<_>>();
+        if output_dir.join(POSTER).exists() {
+            debug!("thumbnails. Skipping");
+            return Ok(thumbnails);
+        }
+
+        let frame = |position: f32, height: usize, filename: &str| {
+            let mut cmd = Command::new("ffmpeg");
+            cmd.kill_on_drop(true)
+                .arg("-y")
+                // seeking before the input is much faster
+                .args(&["-ss", &format!("{:.3}", position)])
+                .args(&["-i", self.path.to_str().unwrap()])
+                .args(&["-frames:v", "1"])
+                .args(&[
+                    "-vf",
+                    &format!("scale=-2:{}", min(height, self.video_height)),
+                ])
+                .args(&["-q:v", "3"])
+                .args(&[output_dir.join(filename).to_str().unwrap()]);
+
+            cmd
+        };
+
+        let mut commands = thumbnails
+            .iter()
+            .enumerate()
+            .map(|(i, filename)| {
+                let position = self.duration * (i + 1) as f32 / (THUMBNAIL_CANDIDATES + 1) as f32;
+                frame(position, 180, filename)
+            })
+            .collect::<Vec<_>>();
+        // the poster goes last, so that its presence means that every frame was extracted
+        commands.push(frame(self.duration * 0.1, 720, POSTER));
+
+        self.run_commands("thumbnails", commands).await?;
+
+        Ok(thumbnails)
+    }
+
+    /// Segments the H.264 variants in fMP4 fragments, described both by an HLS master playlist
+    /// and by a DASH manifest. The video streams are copied, so this is quick compared to the
+    /// encoding itself.
+    ///
+    /// Returns `false` if there's nothing to package
+    async fn package(
+        &self,
+        output_dir: &Path,
+        variants: &[(usize, String, String)],
+    ) -> Result<bool, EncoderError> {
+        let adaptive_dir = output_dir.join(ADAPTIVE_DIR);
+        if adaptive_dir.join(DASH_MANIFEST).exists() {
+            debug!("{}. Skipping", ADAPTIVE_DIR);
+            return Ok(true);
+        }
+
+        // VP9 in fMP4 isn't supported by every HLS player, H.264 is
+        let mut inputs = variants
+            .iter()
+            .filter(|(_, _, filename)| filename.starts_with(H264::NAME))
+            .collect::<Vec<_>>();
+        if inputs.is_empty() {
+            return Ok(false);
+        }
+        // the audio is taken from the highest quality
+        inputs.sort_by(|a, b| b.0.cmp(&a.0));
+
+        let partial = adaptive_dir.with_extension("partial");
+        if partial.exists() {
+            tokio::fs::remove_dir_all(&partial).await?;
+        }
+        tokio::fs::create_dir_all(&partial).await?;
+
+        let mut cmd = Command::new("ffmpeg");
+        cmd.current_dir(&partial)
+            .kill_on_drop(true)
+            .arg("-y")
+            .args(&["-progress", "pipe:1"])
+            .arg("-nostats");
+        for (_, _, filename) in &inputs {
+            cmd.args(&["-i", output_dir.join(filename).to_str().unwrap()]);
+        }
+        for i in 0..inputs.len() {
+            cmd.args(&["-map", &format!("{}:v", i)]);
+        }
+        cmd.args(&["-map", "0:a?"])
+            .args(&["-c", "copy"])
+            .args(&["-f", "dash"])
+            .args(&["-seg_duration", &format!("{}", SEGMENT_DURATION)])
+            .args(&["-use_template", "1"])
+            .args(&["-use_timeline", "1"])
+            .args(&["-adaptation_sets", "id=0,streams=v id=1,streams=a"])
+            .args(&["-init_seg_name", "init_$RepresentationID$.mp4"])
+            .args(&[
+                "-media_seg_name",
+                "chunk_$RepresentationID$_$Number%05d$.m4s",
+            ])
+            // also writes `master.m3u8` and a media playlist for every representation
+            .args(&["-hls_playlist", "1"])
+            .arg(DASH_MANIFEST);
+
+        let result = self.run_commands(ADAPTIVE_DIR, vec![cmd]).await;
+        if result.is_err() {
+            tokio::fs::remove_dir_all(&partial).await?;
+        }
+        result?;
+
+        tokio::fs::rename(&partial, &adaptive_dir).await?;
+
+        Ok(true)
+    }
+}
+
+/// Directory containing the segmented output, inside the output directory of a video
+const ADAPTIVE_DIR: &str = "adaptive";
+const HLS_MASTER: &str = "master.m3u8";
+const DASH_MANIFEST: &str = "manifest.mpd";
+const POSTER: &str = "poster.jpg";
+const THUMBNAIL_CANDIDATES: usize = 4;
+
+/// Directory containing the seek preview sprites, inside the output directory of a video
+const TRICKPLAY_DIR: &str = "trickplay";
+const TRICKPLAY_VTT: &str = "thumbnails.vtt";
+/// Seconds between two frames of the seek preview
+const TRICKPLAY_INTERVAL: usize = 10;
+const TRICKPLAY_WIDTH: usize = 160;
+const TRICKPLAY_COLUMNS: usize = 10;
+const TRICKPLAY_ROWS: usize = 10;
+
+/// Length of the adaptive segments in seconds. The H.264 variants have a keyframe at this
+/// interval, so that every segment of every rendition starts at the same time
+const SEGMENT_DURATION: usize = 4;
+
+/// Joins the segments without re-encoding them, using ffmpeg's concat demuxer
+async fn concat_segments(segments: &[PathBuf], output: &Path) -> Result<(), EncoderError> {
+    let list = segments
+        .iter()
+        .map(|segment| format!("file '{}'\n", segment.display()))
+        .collect::<String>();
+
+    let mut list_path = env::temp_dir();
+    let rand_string: String = thread_rng().sample_iter(&Alphanumeric).take(30).collect();
+    list_path.push(format!("{}.txt", rand_string));
+    tokio::fs::write(&list_path, list).await?;
+
+    debug!("Joining {} segments -> {}", segments.len(), output.display());
+
+    // write somewhere else first, so that an interrupted run is never mistaken for a complete one
+    let partial = output.with_extension("partial");
+
+    let mut cmd = Command::new("ffmpeg");
+    cmd.kill_on_drop(true)
+        .arg("-y")
+        .args(&["-f", "concat"])
+        .args(&["-safe", "0"])
+        .args(&["-i", list_path.to_str().unwrap()])
+        .args(&["-c", "copy"])
+        .args(&["-f", "flv"])
+        .args(&[partial.to_str().unwrap()]);
+    trace!("{:?}", cmd);
+
+    let result = cmd.output().await?;
+    tokio::fs::remove_file(&list_path).await?;
+
+    if !result.status.success() {
+        return Err(EncoderError::CommandFailed {
+            code: result.status.code(),
+            stderr: tail(&String::from_utf8_lossy(&result.stderr), 20),
+        });
+    }
+    tokio::fs::rename(&partial, output).await?;
+
+    Ok(())
+}
+
+/// A variant still to be encoded
+struct VariantOutput {
+    name: String,
+    codec: &'static str,
+    passes: usize,
+    output_args: fn(&mut Command, usize, &str, &VariantOutput, usize),
+    threads: usize,
+
+    height: usize,
+    /// Frame rate to reduce the source to, if it's higher than the `max_fps` of the variant
+    fps: Option<f32>,
+    bitrate: usize,
+    audio_channels: usize,
+
+    partial: PathBuf,
+    output: PathBuf,
+}
+
+/// Builds the ffmpeg invocations producing all the `outputs` at once: the source is decoded only
+/// one time per pass, and split into a scaled stream for each output
+fn commands(
+    outputs: &[VariantOutput],
+    audio_sampling: usize,
+    input: &Path,
+    workdir: &Path,
+) -> Vec<Command> {
+    let passes = outputs.iter().map(|o| o.passes).max().unwrap_or(0);
+
+    (1..=passes)
+        .map(|pass| {
+            // codecs with fewer passes only join the last invocations
+            let outputs = outputs
+                .iter()
+                .filter(|o| pass > passes - o.passes)
+                .collect::<Vec<_>>();
+            let scales = outputs
+                .iter()
+                .map(|o| (o.height, o.fps))
+                .collect::<Vec<_>>();
+
+            let mut command = Command::new("ffmpeg");
+            command
+                .current_dir(workdir)
+                .kill_on_drop(true)
+                .arg("-y")
+                .args(&["-progress", "pipe:1"])
+                .arg("-nostats")
+                .args(&["-i", input.to_str().unwrap()])
+                .args(&["-filter_complex", &filter_graph(&scales)]);
+
+            for (i, output) in outputs.iter().enumerate() {
+                (output.output_args)(
+                    &mut command,
+                    pass - (passes - output.passes),
+                    &format!("[v{}]", i),
+                    output,
+                    audio_sampling,
+                );
+            }
+
+            command
         })
+        .collect()
+}
+
+/// Filter graph splitting the source video in one stream for each height, capped to its frame rate
+/// if any, labeled `[v<i>]`
+fn filter_graph(scales: &[(usize, Option<f32>)]) -> String {
+    let filters = |(height, fps): &(usize, Option<f32>)| match fps {
+        Some(fps) => format!("scale=-2:{},fps=fps={}", height, fps),
+        None => format!("scale=-2:{}", height),
+    };
+
+    if scales.len() == 1 {
+        return format!("[0:v]{}[v0]", filters(&scales[0]));
     }
+
+    let split = (0..scales.len())
+        .map(|i| format!("[s{}]", i))
+        .collect::<String>();
+    let outputs = scales
+        .iter()
+        .enumerate()
+        .map(|(i, scale)| format!("[s{}]{}[v{}]", i, filters(scale), i))
+        .collect::<Vec<_>>();
+
+    format!("[0:v]split={}{};{}", scales.len(), split, outputs.join(";"))
 }
 
 trait Codec {
-    fn commands(
-        bitrate: usize,
-        height: usize,
-        fps: f32,
-        audio_channels: usize,
+    const NAME: &'static str;
+    const EXTENSION: &'static str;
+    const MIME: &'static str;
+    const PASSES: usize;
+
+    /// Appends to `command` the arguments of an output encoding the `video` stream, for
+    /// `pass` (starting from 1)
+    fn output_args(
+        command: &mut Command,
+        pass: usize,
+        video: &str,
+        output: &VariantOutput,
         audio_sampling: usize,
-        input: &Path,
-        output: &Path,
-        workdir: &Path,
-    ) -> Result<Vec<Command>, EncoderError>;
+    );
 }
 
 struct VP9;
 impl Codec for VP9 {
-    fn commands(
-        bitrate: usize,
-        height: usize,
-        fps: f32,
-        audio_channels: usize,
+    const NAME: &'static str = "vp9";
+    const EXTENSION: &'static str = "webm";
+    const MIME: &'static str = "video/webm";
+    const PASSES: usize = 2;
+
+    fn output_args(
+        command: &mut Command,
+        pass: usize,
+        video: &str,
+        output: &VariantOutput,
         audio_sampling: usize,
-        input: &Path,
-        output: &Path,
-        workdir: &Path,
-    ) -> Result<Vec<Command>, EncoderError> {
-        let mut command_1 = Command::new("ffmpeg");
-        command_1
-            .current_dir(workdir)
-            .kill_on_drop(true)
-            .arg("-y")
-            .args(&["-i", input.to_str().unwrap()])
-            .args(&["-vf", &format!("scale=-2:{}", height)])
-            // .args(&["-filter:v", &format!("fps=fps={}", fps)]) TODO merge with scaling
-            .args(&["-c:v", "libvpx-vp9"])
-            .args(&["-b:v", &format!("{}K", bitrate)])
-            .args(&["-threads", "4"])
-            .args(&["-row-mt", "1"])
-            .args(&["-pass", "1"])
-            .args(&["-an"])
-            .args(&["-f", "webm"])
-            .args(&["/dev/null"]);
-        let mut command_2 = Command::new("ffmpeg");
-        command_2
-            .current_dir(workdir)
-            .kill_on_drop(true)
-            .arg("-y")
-            .args(&["-i", input.to_str().unwrap()])
-            .args(&["-vf", &format!("scale=-2:{}", height)])
-            // .args(&["-filter:v", &format!("fps=fps={}", fps)])
+    ) {
+        command
+            .args(&["-map", video])
             .args(&["-c:v", "libvpx-vp9"])
-            .args(&["-b:v", &format!("{}K", bitrate)])
-            .args(&["-threads", "4"])
+            .args(&["-b:v", &format!("{}K", output.bitrate)])
+            .args(&["-threads", &format!("{}", output.threads)])
             .args(&["-row-mt", "1"])
-            .args(&["-pass", "2"])
-            .args(&["-c:a", "libopus"])
-            .args(&["-ac", &format!("{}", audio_channels)])
-            .args(&["-ar", &format!("{}", audio_sampling)])
-            .args(&["-f", "webm"])
-            .args(&[output.to_str().unwrap()]);
+            .args(&["-pass", &format!("{}", pass)])
+            // every output needs its own stats when they are encoded together
+            .args(&["-passlogfile", &output.name]);
 
-        Ok(vec![command_1, command_2])
+        if pass == 1 {
+            command
+                .args(&["-an"])
+                .args(&["-f", "webm"])
+                .args(&["/dev/null"]);
+        } else {
+            command
+                .args(&["-map", "0:a?"])
+                .args(&["-c:a", "libopus"])
+                .args(&["-ac", &format!("{}", output.audio_channels)])
+                .args(&["-ar", &format!("{}", audio_sampling)])
+                .args(&["-f", "webm"])
+                .args(&[output.partial.to_str().unwrap()]);
+        }
     }
 }
 
 struct H264;
 impl Codec for H264 {
-    fn commands(
-        bitrate: usize,
-        height: usize,
-        fps: f32,
-        audio_channels: usize,
+    const NAME: &'static str = "h264";
+    const EXTENSION: &'static str = "mp4";
+    const MIME: &'static str = "video/mp4";
+    const PASSES: usize = 1;
+
+    fn output_args(
+        command: &mut Command,
+        _pass: usize,
+        video: &str,
+        output: &VariantOutput,
         audio_sampling: usize,
-        input: &Path,
-        output: &Path,
-        workdir: &Path,
-    ) -> Result<Vec<Command>, EncoderError> {
-        let mut command = Command::new("ffmpeg");
+    ) {
         command
-            .current_dir(workdir)
-            .kill_on_drop(true)
-            .arg("-y")
-            .args(&["-i", input.to_str().unwrap()])
-            .args(&["-vf", &format!("scale=-2:{}", height)])
-            // .args(&["-filter:v", &format!("fps=fps={}", fps)])
+            .args(&["-map", video])
+            .args(&["-map", "0:a?"])
             .args(&["-c:v", "libx264"])
-            .args(&["-b:v", &format!("{}K", bitrate)])
+            .args(&["-threads", &format!("{}", output.threads)])
+            .args(&["-b:v", &format!("{}K", output.bitrate)])
+            .args(&[
+                "-force_key_frames",
+                &format!("expr:gte(t,n_forced*{})", SEGMENT_DURATION),
+            ])
             .args(&["-c:a", "aac"])
-            .args(&["-ac", &format!("{}", audio_channels)])
+            .args(&["-ac", &format!("{}", output.audio_channels)])
             .args(&["-ar", &format!("{}", audio_sampling)])
             .args(&["-f", "mp4"])
-            .args(&[output.to_str().unwrap()]);
-        Ok(vec![command])
+            .args(&[output.partial.to_str().unwrap()]);
     }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct EncodingConfiguration {
     pub vod: EncodingCodecs,
+    /// Renditions transcoded while streaming
+    #[serde(default)]
+    pub live: HashMap<String, LiveVariant>,
+}
+
+impl EncodingConfiguration {
+    pub async fn load() -> Result<Self, EncoderError> {
+        let mut contents = vec![];
+
+        let mut config_file = File::open("encoding.toml").await?;
+        config_file.read_to_end(&mut contents).await?;
+
+        Ok(toml::from_slice(&contents)?)
+    }
+
+    pub fn sync_load() -> Result<Self, EncoderError> {
+        Ok(toml::from_slice(&std::fs::read("encoding.toml")?)?)
+    }
+
+    /// Names of the logs that may be written for a video, in any of the encoding modes
+    pub fn log_names(&self) -> Vec<String> {
+        let variants = |codec: &str, configs: &HashMap<String, EncodingVariant>| {
+            configs
+                .keys()
+                .map(|id| format!("{}_{}", codec, id))
+                .collect::<Vec<_>>()
+        };
+
+        let mut names = vec![
+            VP9::NAME.to_string(),
+            H264::NAME.to_string(),
+            "all".to_string(),
+            "thumbnails".to_string(),
+            TRICKPLAY_DIR.to_string(),
+            ADAPTIVE_DIR.to_string(),
+            // written by the live transcoder
+            "live".to_string(),
+        ];
+        names.extend(variants(VP9::NAME, &self.vod.vp9));
+        names.extend(variants(H264::NAME, &self.vod.h264));
+
+        names
+    }
+}
+
+/// How the variants are split between ffmpeg invocations
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncodingMode {
+    /// One invocation for each variant
+    Separate,
+    /// One invocation for all the variants of a codec
+    PerCodec,
+    /// One invocation for all the variants
+    Single,
+}
+
+impl Default for EncodingMode {
+    fn default() -> Self {
+        EncodingMode::PerCodec
+    }
+}
+
+fn default_concurrency() -> usize {
+    1
+}
+
+fn default_threads() -> usize {
+    4
 }
 
 #[derive(Debug, Deserialize)]
 pub struct EncodingCodecs {
+    #[serde(default)]
+    pub mode: EncodingMode,
+    /// How many groups of variants are encoded at the same time
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// Threads given to each encoder
+    #[serde(default = "default_threads")]
+    pub threads: usize,
+
     #[serde(default)]
     pub vp9: HashMap<String, EncodingVariant>,
 
@@ -324,6 +922,85 @@ pub struct EncodingVariant {
     pub max_fps: Option<f32>,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct LiveVariant {
+    pub height: usize,
+    pub bitrate: usize,
+    pub audio_channels: usize,
+    /// x264 preset, faster ones leave more room for the other renditions
+    #[serde(default = "default_live_preset")]
+    pub preset: String,
+}
+
+fn default_live_preset() -> String {
+    "fast".into()
+}
+
+/// Parses the position reached by ffmpeg, in seconds, from a line of its `-progress` output
+fn parse_out_time(line: &str) -> Option<f32> {
+    // `out_time_ms` is in microseconds too, and it's the only one printed by older versions
+    let value = if line.starts_with("out_time_us=") {
+        &line["out_time_us=".len()..]
+    } else if line.starts_with("out_time_ms=") {
+        &line["out_time_ms=".len()..]
+    } else {
+        return None;
+    };
+
+    value
+        .trim()
+        .parse::<i64>()
+        .ok()
+        .map(|us| us.max(0) as f32 / 1e6)
+}
+
+/// WebVTT file mapping every `TRICKPLAY_INTERVAL` seconds of the video to its tile in the sprites
+/// produced by ffmpeg's `tile` filter, numbered from `sprite_001.jpg`
+fn trickplay_vtt(duration: f32, width: usize, height: usize) -> String {
+    let per_sprite = TRICKPLAY_COLUMNS * TRICKPLAY_ROWS;
+    let frames = (duration / TRICKPLAY_INTERVAL as f32).ceil() as usize;
+
+    let mut vtt = String::from("WEBVTT\n");
+    for frame in 0..frames {
+        let start = (frame * TRICKPLAY_INTERVAL) as f32;
+        let end = min(((frame + 1) * TRICKPLAY_INTERVAL) as f32, duration);
+        let tile = frame % per_sprite;
+
+        vtt.push_str(&format!(
+            "\n{} --> {}\nsprite_{:03}.jpg#xywh={},{},{},{}\n",
+            vtt_timestamp(start),
+            vtt_timestamp(end),
+            frame / per_sprite + 1,
+            (tile % TRICKPLAY_COLUMNS) * width,
+            (tile / TRICKPLAY_COLUMNS) * height,
+            width,
+            height
+        ));
+    }
+
+    vtt
+}
+
+fn vtt_timestamp(seconds: f32) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Last `lines` lines of `text`
+fn tail(text: &str, lines: usize) -> String {
+    let mut tail = text.lines().rev().take(lines).collect::<Vec<_>>();
+    tail.reverse();
+
+    tail.join("\n")
+}
+
 fn min<T: PartialOrd>(a: T, b: T) -> T {
     if a < b {
         a
@@ -335,7 +1012,12 @@ fn min<T: PartialOrd>(a: T, b: T) -> T {
 #[derive(Debug)]
 pub enum EncoderError {
     MissingInput,
-    VideoProbe(crate::probe::VideoProbeError),
+    VideoProbe(VideoProbeError),
+    CommandFailed {
+        code: Option<i32>,
+        /// Last lines printed by the command on stderr
+        stderr: String,
+    },
 
     TokioIO(tokio::io::Error),
     TOML(toml::de::Error),
@@ -353,8 +1035,8 @@ impl From<toml::de::Error> for EncoderError {
     }
 }
 
-impl From<crate::probe::VideoProbeError> for EncoderError {
-    fn from(other: crate::probe::VideoProbeError) -> Self {
+impl From<VideoProbeError> for EncoderError {
+    fn from(other: VideoProbeError) -> Self {
         EncoderError::VideoProbe(other)
     }
 }
@@ -386,4 +1068,115 @@ mod test {
 
         println!("{:#?}", result);
     }
+
+    #[test]
+    fn test_encoder_decode_mode() {
+        let result: EncodingConfiguration = toml::from_str(
+            r#"
+            [vod]
+            mode = "per_codec"
+
+            [vod.h264]
+            240p = { height = 240, bitrate = 242, audio_channels = 1, max_fps = 24 }
+        "#,
+        )
+        .unwrap();
+
+        match result.vod.mode {
+            EncodingMode::PerCodec => {}
+            mode => panic!("Unexpected mode {:?}", mode),
+        }
+    }
+
+    #[test]
+    fn test_encoder_decode_live() {
+        let result: EncodingConfiguration = toml::from_str(
+            r#"
+            [vod.h264]
+            240p = { height = 240, bitrate = 242, audio_channels = 1, max_fps = 24 }
+
+            [live]
+            240p = { height = 240, bitrate = 200, audio_channels = 1, preset = "faster" }
+            720p = { height = 720, bitrate = 1300, audio_channels = 2 }
+        "#,
+        )
+        .unwrap();
+
+        assert_eq!(result.live.len(), 2);
+        assert_eq!(result.live["240p"].preset, "faster");
+        assert_eq!(result.live["720p"].preset, "fast");
+    }
+
+    #[test]
+    fn test_filter_graph() {
+        assert_eq!(filter_graph(&[(240, None)]), "[0:v]scale=-2:240[v0]");
+        assert_eq!(
+            filter_graph(&[(240, Some(24.0)), (720, None)]),
+            "[0:v]split=2[s0][s1];[s0]scale=-2:240,fps=fps=24[v0];[s1]scale=-2:720[v1]"
+        );
+    }
+
+    #[test]
+    fn test_trickplay_vtt() {
+        let vtt = trickplay_vtt(1005.5, 160, 90);
+        let cues = vtt.split("\n\n").skip(1).collect::<Vec<_>>();
+
+        assert_eq!(cues.len(), 101);
+        assert_eq!(
+            cues[0],
+            "00:00:00.000 --> 00:00:10.000\nsprite_001.jpg#xywh=0,0,160,90"
+        );
+        assert_eq!(
+            cues[99],
+            "00:16:30.000 --> 00:16:40.000\nsprite_001.jpg#xywh=1440,810,160,90"
+        );
+        assert_eq!(
+            cues[100],
+            "00:16:40.000 --> 00:16:45.500\nsprite_002.jpg#xywh=0,0,160,90\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_out_time() {
+        assert_eq!(parse_out_time("out_time_us=12500000"), Some(12.5));
+        assert_eq!(parse_out_time("out_time_ms=12500000"), Some(12.5));
+        assert_eq!(parse_out_time("out_time_us=N/A"), None);
+        assert_eq!(
+            parse_out_time("out_time_us=-9223372036854775807"),
+            Some(0.0)
+        );
+        assert_eq!(parse_out_time("out_time=00:00:12.500000"), None);
+        assert_eq!(parse_out_time("progress=continue"), None);
+    }
+
+    #[test]
+    fn test_recording_segments_order() {
+        let rand_string: String = thread_rng().sample_iter(&Alphanumeric).take(30).collect();
+        let storage_dir = env::temp_dir().join(rand_string);
+        let recordings_dir = storage_dir.join("recordings");
+        std::fs::create_dir_all(&recordings_dir).unwrap();
+
+        for name in &[
+            "video-1590000100.flv",
+            "video.flv",
+            "video-1590000000.flv",
+            "video.joined.flv",
+            "video-abc.flv",
+            "other-1590000000.flv",
+        ] {
+            std::fs::write(recordings_dir.join(name), b"").unwrap();
+        }
+
+        let segments = recording_segments(storage_dir.to_str().unwrap(), "video").unwrap();
+        std::fs::remove_dir_all(&storage_dir).unwrap();
+
+        assert_eq!(
+            segments,
+            vec![
+                recordings_dir.join("video.flv"),
+                recordings_dir.join("video-1590000000.flv"),
+                recordings_dir.join("video-1590000100.flv"),
+            ]
+        );
+    }
 }
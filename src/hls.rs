@@ -0,0 +1,215 @@
+//! Minimal parsing and rendering of the HLS media playlists written by nginx-rtmp
+
+use crate::types::LiveRendition;
+
+/// Master playlist listing `renditions`, each one pointing to the media playlist at `uri`
+pub fn master_playlist<F: Fn(&LiveRendition) -> String>(
+    renditions: &[LiveRendition],
+    uri: F,
+) -> String {
+    let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+    for rendition in renditions {
+        playlist.push_str(&format!(
+            "#EXT-X-STREAM-INF:BANDWIDTH={},RESOLUTION={}x{}",
+            rendition.bandwidth.max(1),
+            rendition.width,
+            rendition.height
+        ));
+        if !rendition.codecs.is_empty() {
+            playlist.push_str(&format!(",CODECS=\"{}\"", rendition.codecs.join(",")));
+        }
+        playlist.push_str(&format!("\n{}\n", uri(rendition)));
+    }
+
+    playlist
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    /// In seconds
+    pub duration: f32,
+    pub uri: String,
+    /// Whether the segment is preceded by `#EXT-X-DISCONTINUITY`
+    pub discontinuity: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MediaPlaylist {
+    pub target_duration: u64,
+    pub media_sequence: u64,
+    pub discontinuity_sequence: u64,
+    pub segments: Vec<Segment>,
+    /// Whether the playlist is declared with `#EXT-X-PLAYLIST-TYPE:EVENT`
+    pub event: bool,
+    /// Whether the playlist ends with `#EXT-X-ENDLIST`
+    pub ended: bool,
+}
+
+impl MediaPlaylist {
+    /// Parses a playlist, ignoring every tag that isn't needed to re-render it
+    pub fn parse(text: &str) -> Self {
+        let mut playlist = MediaPlaylist::default();
+
+        let mut duration = None;
+        let mut discontinuity = false;
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            if line.starts_with("#EXT-X-TARGETDURATION:") {
+                playlist.target_duration = parse_value(line).unwrap_or(0);
+            } else if line.starts_with("#EXT-X-MEDIA-SEQUENCE:") {
+                playlist.media_sequence = parse_value(line).unwrap_or(0);
+            } else if line.starts_with("#EXT-X-DISCONTINUITY-SEQUENCE:") {
+                playlist.discontinuity_sequence = parse_value(line).unwrap_or(0);
+            } else if line == "#EXT-X-PLAYLIST-TYPE:EVENT" {
+                playlist.event = true;
+            } else if line == "#EXT-X-DISCONTINUITY" {
+                discontinuity = true;
+            } else if line == "#EXT-X-ENDLIST" {
+                playlist.ended = true;
+            } else if line.starts_with("#EXTINF:") {
+                // `#EXTINF:<duration>,[<title>]`
+                duration = line["#EXTINF:".len()..]
+                    .split(',')
+                    .nth(0)
+                    .and_then(|d| d.parse::<f32>().ok());
+            } else if !line.starts_with('#') {
+                playlist.segments.push(Segment {
+                    duration: duration.take().unwrap_or(0.0),
+                    uri: line.to_string(),
+                    discontinuity,
+                });
+                discontinuity = false;
+            }
+        }
+
+        playlist
+    }
+
+    /// Total duration of the segments, in seconds
+    pub fn duration(&self) -> f32 {
+        self.segments.iter().map(|s| s.duration).sum()
+    }
+
+    /// Drops the oldest segments, keeping at most the last `window` seconds
+    pub fn trim(&mut self, window: f32) {
+        let mut kept_duration = 0.0;
+        let kept = self
+            .segments
+            .iter()
+            .rev()
+            .take_while(|s| {
+                kept_duration += s.duration;
+                kept_duration <= window
+            })
+            .count()
+            // never return an empty playlist
+            .max(1)
+            .min(self.segments.len());
+
+        let dropped = self.segments.len() - kept;
+        self.media_sequence += dropped as u64;
+        self.discontinuity_sequence += self.segments[..dropped]
+            .iter()
+            .filter(|s| s.discontinuity)
+            .count() as u64;
+        self.segments.drain(..dropped);
+    }
+
+    /// Makes the relative segment URIs absolute, so that they can be served from somewhere else
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        for segment in &mut self.segments {
+            if !segment.uri.contains("://") && !segment.uri.starts_with('/') {
+                segment.uri = format!("{}/{}", base_url.trim_end_matches('/'), segment.uri);
+            }
+        }
+
+        self
+    }
+
+    pub fn render(&self) -> String {
+        let mut text = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+        text.push_str(&format!("#EXT-X-TARGETDURATION:{}\n", self.target_duration));
+        text.push_str(&format!("#EXT-X-MEDIA-SEQUENCE:{}\n", self.media_sequence));
+        if self.event {
+            text.push_str("#EXT-X-PLAYLIST-TYPE:EVENT\n");
+        }
+        if self.discontinuity_sequence > 0 {
+            text.push_str(&format!(
+                "#EXT-X-DISCONTINUITY-SEQUENCE:{}\n",
+                self.discontinuity_sequence
+            ));
+        }
+
+        for segment in &self.segments {
+            if segment.discontinuity {
+                text.push_str("#EXT-X-DISCONTINUITY\n");
+            }
+            text.push_str(&format!(
+                "#EXTINF:{:.3},\n{}\n",
+                segment.duration, segment.uri
+            ));
+        }
+
+        if self.ended {
+            text.push_str("#EXT-X-ENDLIST\n");
+        }
+
+        text
+    }
+}
+
+fn parse_value(line: &str) -> Option<u64> {
+    line.splitn(2, ':').nth(1)?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PLAYLIST: &str = "#EXTM3U
+#EXT-X-VERSION:3
+#EXT-X-MEDIA-SEQUENCE:7
+#EXT-X-TARGETDURATION:5
+#EXTINF:4.000,
+movie_240-7.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:4.500,
+movie_240-8.ts
+#EXTINF:4.000,
+movie_240-9.ts
+";
+
+    #[test]
+    fn test_parse() {
+        let playlist = MediaPlaylist::parse(PLAYLIST);
+
+        assert_eq!(playlist.target_duration, 5);
+        assert_eq!(playlist.media_sequence, 7);
+        assert_eq!(playlist.segments.len(), 3);
+        assert_eq!(playlist.duration(), 12.5);
+        assert!(playlist.segments[1].discontinuity);
+        assert!(!playlist.ended);
+    }
+
+    #[test]
+    fn test_trim() {
+        let mut playlist = MediaPlaylist::parse(PLAYLIST);
+        playlist.trim(6.0);
+
+        assert_eq!(playlist.media_sequence, 9);
+        assert_eq!(playlist.discontinuity_sequence, 1);
+        assert_eq!(playlist.segments.len(), 1);
+        assert_eq!(playlist.segments[0].uri, "movie_240-9.ts");
+    }
+
+    #[test]
+    fn test_render() {
+        let playlist = MediaPlaylist::parse(PLAYLIST).with_base_url("https://cdn/hls/movie_240/");
+        let rendered = playlist.render();
+
+        assert!(rendered.contains("#EXT-X-MEDIA-SEQUENCE:7\n"));
+        assert!(rendered.contains(
+            "#EXT-X-DISCONTINUITY\n#EXTINF:4.500,\nhttps://cdn/hls/movie_240/movie_240-8.ts\n"
+        ));
+        assert!(!rendered.contains("#EXT-X-ENDLIST"));
+    }
+}
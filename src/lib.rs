@@ -0,0 +1,18 @@
+#![feature(proc_macro_hygiene, decl_macro)]
+
+#[macro_use]
+extern crate async_trait;
+
+pub mod api;
+pub mod chat;
+pub mod config;
+pub mod db;
+pub mod encoder;
+pub mod hls;
+pub mod moderation;
+pub mod monitor;
+pub mod probe;
+pub mod ratelimit;
+pub mod tasks;
+pub mod types;
+pub mod ws;
@@ -1,8 +1,3 @@
-#![feature(proc_macro_hygiene, decl_macro)]
-
-#[macro_use]
-extern crate async_trait;
-
 use std::env;
 use std::sync::Arc;
 
@@ -10,15 +5,7 @@ use tokio::task;
 
 use btcpay::*;
 
-mod api;
-mod config;
-mod db;
-mod encoder;
-mod monitor;
-mod probe;
-mod tasks;
-mod types;
-mod ws;
+use selfstream::{api, config, db, monitor, tasks, ws};
 
 #[tokio::main]
 async fn main() {
@@ -39,8 +26,9 @@ async fn main() {
 
     let cloned_config = config.clone();
     let cloned_db = db.clone();
+    let runtime = tokio::runtime::Handle::current();
     std::thread::spawn(move || {
-        api::start(cloned_db, cloned_config);
+        api::start(cloned_db, cloned_config, runtime);
     });
 
     let cloned_config = config.clone();
@@ -50,6 +38,22 @@ async fn main() {
         tasks::live_monitor::monitor_live_streams(cloned_db, cloned_monitor, cloned_config).await;
     });
 
+    let cloned_config = config.clone();
+    let cloned_db = db.clone();
+    task::spawn(async move {
+        tasks::live_thumbnails::capture_live_thumbnails(cloned_db, cloned_config).await;
+    });
+
+    if config.local_worker {
+        let cloned_config = config.clone();
+        let cloned_db = db.clone();
+        task::spawn(async move {
+            tasks::encoding_queue::run_worker(cloned_db, cloned_config, "local".into())
+                .await
+                .unwrap();
+        });
+    }
+
     let btcpay_key =
         SecretKey::from_slice(&Vec::<u8>::from_hex(&config.btcpay.key).unwrap()).unwrap();
     let btcpay_keypair: KeyPair = btcpay_key.into();
@@ -0,0 +1,186 @@
+//! Timeouts, bans and slow mode of the chat, enforced by the websocket server.
+//!
+//! A new username is assigned on every connection, so the sanctions are applied to the IP the
+//! user is connected from, looked up from their username while they are still in the chat
+
+use redis::aio::MultiplexedConnection;
+use redis::{Connection, RedisResult};
+
+use crate::types::WsPacket;
+
+/// Last IP every username connected from
+const ADDRESSES_KEY: &str = "chat_addresses";
+
+fn ban_key(ip: &str) -> String {
+    format!("chat_ban:{}", ip)
+}
+
+fn timeout_key(ip: &str) -> String {
+    format!("chat_timeout:{}", ip)
+}
+
+fn slow_mode_key(room: &str) -> String {
+    format!("chat_slow_mode:{}", room)
+}
+
+fn last_message_key(room: &str, ip: &str) -> String {
+    format!("chat_last_message:{}:{}", room, ip)
+}
+
+/// Returns `false` if the username is already taken
+pub async fn register(
+    con: &mut MultiplexedConnection,
+    username: &str,
+    ip: &str,
+) -> RedisResult<bool> {
+    redis::cmd("HSETNX")
+        .arg(ADDRESSES_KEY)
+        .arg(username)
+        .arg(ip)
+        .query_async(con)
+        .await
+}
+
+pub async fn unregister(con: &mut MultiplexedConnection, username: &str) -> RedisResult<()> {
+    redis::cmd("HDEL")
+        .arg(ADDRESSES_KEY)
+        .arg(username)
+        .query_async(con)
+        .await
+}
+
+async fn address(con: &mut MultiplexedConnection, username: &str) -> RedisResult<Option<String>> {
+    redis::cmd("HGET")
+        .arg(ADDRESSES_KEY)
+        .arg(username)
+        .query_async(con)
+        .await
+}
+
+pub async fn is_banned(con: &mut MultiplexedConnection, ip: &str) -> RedisResult<bool> {
+    redis::cmd("EXISTS").arg(ban_key(ip)).query_async(con).await
+}
+
+pub async fn is_timed_out(con: &mut MultiplexedConnection, ip: &str) -> RedisResult<bool> {
+    redis::cmd("EXISTS")
+        .arg(timeout_key(ip))
+        .query_async(con)
+        .await
+}
+
+/// Sets `key` on the IP of a user for `duration` seconds, returning the IP or `None` if the user
+/// isn't in the chat
+async fn sanction(
+    con: &mut MultiplexedConnection,
+    username: &str,
+    key: fn(&str) -> String,
+    duration: u64,
+) -> RedisResult<Option<String>> {
+    let ip = match address(con, username).await? {
+        Some(ip) => ip,
+        None => return Ok(None),
+    };
+
+    let _: () = redis::cmd("SET")
+        .arg(key(&ip))
+        .arg(1)
+        .arg("EX")
+        .arg(duration)
+        .query_async(con)
+        .await?;
+
+    Ok(Some(ip))
+}
+
+/// Bans a user from every chat for `duration` seconds, returning their IP or `None` if they
+/// aren't in the chat
+pub async fn ban(
+    con: &mut MultiplexedConnection,
+    username: &str,
+    duration: u64,
+) -> RedisResult<Option<String>> {
+    sanction(con, username, ban_key, duration).await
+}
+
+/// Lifts the ban of an IP before it expires
+pub fn sync_unban(con: &mut Connection, ip: &str) -> RedisResult<bool> {
+    redis::cmd("DEL").arg(ban_key(ip)).query(con)
+}
+
+/// Mutes a user in every chat for `duration` seconds, returning their IP or `None` if they
+/// aren't in the chat
+pub async fn timeout(
+    con: &mut MultiplexedConnection,
+    username: &str,
+    duration: u64,
+) -> RedisResult<Option<String>> {
+    sanction(con, username, timeout_key, duration).await
+}
+
+/// Seconds every user has to wait between their messages in the room, 0 if slow mode is off
+pub async fn slow_mode(con: &mut MultiplexedConnection, room: &str) -> RedisResult<u64> {
+    let interval: Option<u64> = redis::cmd("GET")
+        .arg(slow_mode_key(room))
+        .query_async(con)
+        .await?;
+
+    Ok(interval.unwrap_or(0))
+}
+
+/// Changes the slow mode of the room, letting everyone in it know
+pub async fn set_slow_mode(
+    con: &mut MultiplexedConnection,
+    room: &str,
+    interval: u64,
+) -> RedisResult<()> {
+    let packet = WsPacket::SlowMode { interval };
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    if interval > 0 {
+        pipe.cmd("SET").arg(slow_mode_key(room)).arg(interval);
+    } else {
+        pipe.cmd("DEL").arg(slow_mode_key(room));
+    }
+    pipe.ignore()
+        .cmd("PUBLISH")
+        .arg(room)
+        .arg(serde_json::to_string(&packet).unwrap())
+        .ignore();
+
+    pipe.query_async(con).await
+}
+
+/// Seconds the user has to wait before sending a message in a room with slow mode on, or `None`
+/// if they can send it now, in which case they'll have to wait `interval` seconds for the next one
+pub async fn slow_mode_wait(
+    con: &mut MultiplexedConnection,
+    room: &str,
+    ip: &str,
+    interval: u64,
+) -> RedisResult<Option<u64>> {
+    // keyed on the address, so that reconnecting doesn't reset the interval
+    let key = last_message_key(room, ip);
+    let (set, ttl): (Option<String>, i64) = redis::pipe()
+        .atomic()
+        .cmd("SET")
+        .arg(&key)
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(interval)
+        .cmd("TTL")
+        .arg(&key)
+        .query_async(con)
+        .await?;
+
+    Ok(match set {
+        Some(_) => None,
+        None => Some(ttl.max(1) as u64),
+    })
+}
+
+/// Removes the slow mode of the room
+pub fn sync_clear(con: &mut Connection, room: &str) -> RedisResult<()> {
+    redis::cmd("DEL").arg(slow_mode_key(room)).query(con)
+}
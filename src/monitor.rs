@@ -123,6 +123,10 @@ pub enum NginxMeta {
         frame_rate: f32,
         codec: String,
         profile: String,
+        #[serde(default)]
+        compat: Option<u8>,
+        #[serde(default)]
+        level: Option<f32>,
     },
     #[serde(rename = "audio")]
     Audio {
@@ -137,6 +141,48 @@ pub enum NginxMeta {
     },
 }
 
+impl NginxMeta {
+    /// RFC 6381 codec string, as used in the `CODECS` attribute of HLS playlists
+    pub fn codecs(&self) -> Option<String> {
+        match self {
+            NginxMeta::Video {
+                codec,
+                profile,
+                compat,
+                level,
+                ..
+            } if codec == "H264" => {
+                let profile_idc = match profile.as_str() {
+                    "Baseline" => 66,
+                    "Main" => 77,
+                    "Extended" => 88,
+                    "High" => 100,
+                    _ => return None,
+                };
+                let level_idc = (level.as_ref()? * 10.0).round() as u8;
+
+                Some(format!(
+                    "avc1.{:02x}{:02x}{:02x}",
+                    profile_idc,
+                    compat.unwrap_or(0),
+                    level_idc
+                ))
+            }
+            NginxMeta::Audio { codec, profile, .. } if codec == "AAC" => {
+                let object_type = match profile.as_str() {
+                    "Main" => 1,
+                    "LC" => 2,
+                    "SBR" | "HE" => 5,
+                    _ => return None,
+                };
+
+                Some(format!("mp4a.40.{}", object_type))
+            }
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum MonitorError {
     Reqwest(reqwest::Error),
@@ -218,5 +264,20 @@ mod test {
 
         let n: NginxStat = from_str(s).unwrap();
         println!("{:#?}", n);
+
+        let stream = n
+            .get_application("hls")
+            .unwrap()
+            .get_stream("movie_480")
+            .unwrap();
+        let codecs = stream
+            .meta
+            .as_ref()
+            .unwrap()
+            .metas
+            .iter()
+            .filter_map(NginxMeta::codecs)
+            .collect::<Vec<_>>();
+        assert_eq!(codecs, vec!["avc1.42c01f", "mp4a.40.2"]);
     }
 }
@@ -90,6 +90,9 @@ impl VideoProbe {
                                 .as_str()
                                 .ok_or(VideoProbeError::MalformedOutput)?
                                 .into(),
+                            compat: None,
+                            // ffprobe reports level 3.1 as `31`
+                            level: s["level"].as_u64().map(|level| level as f32 / 10.0),
                         })
                     }
                     Some("audio") => Ok(NginxMeta::Audio {
@@ -0,0 +1,211 @@
+//! Token buckets limiting how often the chat users can send messages and request invoices, both
+//! for every connection and for every IP
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::{RateLimit, RateLimits};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Request {
+    Message,
+    Invoice,
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    /// Tokens added every second
+    refill: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            capacity: limit.burst as f64,
+            refill: limit.per_minute as f64 / 60.0,
+            tokens: limit.burst as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Takes a token, or returns how long until the next one is available
+    pub fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.check(now)?;
+        self.tokens -= 1.0;
+
+        Ok(())
+    }
+
+    /// Returns how long until the next token is available, without taking it
+    fn check(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+
+        if self.tokens >= 1.0 {
+            Ok(())
+        } else if self.refill > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.refill))
+        } else {
+            Err(Duration::from_secs(u64::MAX))
+        }
+    }
+
+    /// Whether the bucket is back to its capacity, and would behave just like a new one
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+
+        self.tokens >= self.capacity
+    }
+}
+
+/// The buckets of every IP, shared between all the connections
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    ips: Mutex<HashMap<(IpAddr, Request), TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter {
+            limits,
+            ips: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Buckets for a new connection from `ip`
+    pub fn connection(self: &Arc<Self>, ip: IpAddr) -> ConnectionLimiter {
+        let now = Instant::now();
+
+        ConnectionLimiter {
+            limiter: Arc::clone(self),
+            ip,
+            messages: TokenBucket::new(self.limits.messages.connection, now),
+            invoices: TokenBucket::new(self.limits.invoices.connection, now),
+            violations: TokenBucket::new(
+                RateLimit {
+                    burst: self.limits.max_violations,
+                    per_minute: self.limits.max_violations,
+                },
+                now,
+            ),
+        }
+    }
+
+    fn take_ip(&self, ip: IpAddr, request: Request, now: Instant) -> Result<(), Duration> {
+        let mut ips = self.ips.lock().unwrap();
+
+        if !ips.contains_key(&(ip, request)) {
+            // forget the addresses that have been quiet for long enough, before adding a new one
+            ips.retain(|_, bucket| !bucket.is_full(now));
+
+            let limit = match request {
+                Request::Message => self.limits.messages.ip,
+                Request::Invoice => self.limits.invoices.ip,
+            };
+            ips.insert((ip, request), TokenBucket::new(limit, now));
+        }
+
+        ips.get_mut(&(ip, request)).unwrap().take(now)
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    limiter: Arc<RateLimiter>,
+    ip: IpAddr,
+    messages: TokenBucket,
+    invoices: TokenBucket,
+    violations: TokenBucket,
+}
+
+impl ConnectionLimiter {
+    /// Takes a token for the connection and one for its IP, or returns how long to wait
+    pub fn take(&mut self, request: Request) -> Result<(), Duration> {
+        let now = Instant::now();
+
+        let bucket = match request {
+            Request::Message => &mut self.messages,
+            Request::Invoice => &mut self.invoices,
+        };
+        // a request refused by either of them doesn't use up the token of the other
+        bucket.check(now)?;
+        self.limiter.take_ip(self.ip, request, now)?;
+        bucket.take(now)
+    }
+
+    /// Records a request over the limits, returning whether the connection should be closed
+    pub fn violation(&mut self) -> bool {
+        self.violations.take(Instant::now()).is_err()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(
+            RateLimit {
+                burst: 2,
+                per_minute: 30,
+            },
+            start,
+        );
+
+        assert!(bucket.take(start).is_ok());
+        assert!(bucket.take(start).is_ok());
+        assert_eq!(bucket.take(start), Err(Duration::from_secs(2)));
+
+        let later = start + Duration::from_secs(2);
+        assert!(bucket.take(later).is_ok());
+        assert!(bucket.take(later).is_err());
+    }
+
+    #[test]
+    fn test_ip_limits() {
+        let mut limits = RateLimits::default();
+        limits.messages.connection.burst = 10;
+        limits.messages.ip.burst = 3;
+        let limiter = Arc::new(RateLimiter::new(limits));
+        let ip = "127.0.0.1".parse().unwrap();
+
+        let mut first = limiter.connection(ip);
+        let mut second = limiter.connection(ip);
+        assert!(first.take(Request::Message).is_ok());
+        assert!(first.take(Request::Message).is_ok());
+        assert!(second.take(Request::Message).is_ok());
+        assert!(second.take(Request::Message).is_err());
+        // invoices have their own buckets
+        assert!(second.take(Request::Invoice).is_ok());
+    }
+
+    #[test]
+    fn test_refused_by_ip() {
+        let mut limits = RateLimits::default();
+        limits.messages.connection.burst = 2;
+        limits.messages.connection.per_minute = 0;
+        limits.messages.ip.burst = 1;
+        limits.messages.ip.per_minute = 0;
+        let limiter = Arc::new(RateLimiter::new(limits));
+        let ip = "127.0.0.1".parse().unwrap();
+
+        let mut connection = limiter.connection(ip);
+        assert!(connection.take(Request::Message).is_ok());
+        assert!(connection.take(Request::Message).is_err());
+        // the IP refused the second message, so the connection still has a token left
+        assert!(connection.messages.check(Instant::now()).is_ok());
+    }
+}
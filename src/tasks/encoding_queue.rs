@@ -0,0 +1,333 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, error, info};
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+use futures::future::join;
+use futures::StreamExt;
+
+use crate::config::Config;
+use crate::db::{RedisEntity, RedisFetchError, RedisMultiplexed};
+use crate::encoder::{recording_segments, Encoder, EncoderError};
+use crate::tasks::live_archive::ARCHIVE_DIR;
+use crate::types::{
+    EncodingJob, EncodingJobStatus, EncodingProgress, Video, VideoEncodingProgress, VideoStatus,
+    WsPacket,
+};
+
+/// List of the ids of the jobs waiting for a worker, oldest on the right
+const QUEUE_KEY: &str = "encoding_queue";
+
+/// Every worker atomically moves the job it's working on to its own list, so that it can be
+/// recovered if the worker dies halfway through
+fn running_key(worker: &str) -> String {
+    format!("encoding_running:{}", worker)
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+pub fn sync_enqueue(
+    db: &RedisMultiplexed,
+    id: String,
+    timestamp: u64,
+) -> Result<(), RedisFetchError> {
+    let job = EncodingJob {
+        id,
+        timestamp,
+        status: EncodingJobStatus::Queued,
+    };
+    job.sync_save(db)?;
+
+    let mut con = db.get_connection()?;
+    let _: usize = redis::Cmd::lpush(QUEUE_KEY, &job.id).query(&mut con)?;
+
+    info!("Queued encoding of video `{}`", job.id);
+
+    Ok(())
+}
+
+pub async fn enqueue(
+    db: &RedisMultiplexed,
+    id: String,
+    timestamp: u64,
+) -> Result<(), RedisFetchError> {
+    let job = EncodingJob {
+        id,
+        timestamp,
+        status: EncodingJobStatus::Queued,
+    };
+    job.save(db).await?;
+
+    let mut con = db.get_multiplexed_tokio_connection().await?;
+    let _: usize = redis::Cmd::lpush(QUEUE_KEY, &job.id)
+        .query_async(&mut con)
+        .await?;
+
+    info!("Queued encoding of video `{}`", job.id);
+
+    Ok(())
+}
+
+/// Puts back in the queue the jobs the worker was running when it was stopped
+async fn recover(db: &RedisMultiplexed, worker: &str) -> Result<(), RedisFetchError> {
+    let mut con = db.get_multiplexed_tokio_connection().await?;
+
+    loop {
+        let id: Option<String> = redis::Cmd::rpoplpush(running_key(worker), QUEUE_KEY)
+            .query_async(&mut con)
+            .await?;
+        let id = match id {
+            Some(id) => id,
+            None => break,
+        };
+
+        info!("Recovering interrupted encoding of video `{}`", id);
+
+        if let Some(mut job) = EncodingJob::get(db, id).await? {
+            job.status = EncodingJobStatus::Queued;
+            job.save(db).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn claim(db: &RedisMultiplexed, worker: &str) -> Result<Option<String>, RedisFetchError> {
+    let mut con = db.get_multiplexed_tokio_connection().await?;
+
+    Ok(redis::Cmd::rpoplpush(QUEUE_KEY, running_key(worker))
+        .query_async(&mut con)
+        .await?)
+}
+
+async fn release(db: &RedisMultiplexed, worker: &str, id: &str) -> Result<(), RedisFetchError> {
+    let mut con = db.get_multiplexed_tokio_connection().await?;
+    let _: usize = redis::Cmd::lrem(running_key(worker), 0, id)
+        .query_async(&mut con)
+        .await?;
+
+    Ok(())
+}
+
+/// Directory of the live stream archived while the video is `Provisional`
+fn archive_dir(config: &Config, id: &str) -> PathBuf {
+    Path::new(&config.storage_dir)
+        .join("encoded")
+        .join(id)
+        .join(ARCHIVE_DIR)
+}
+
+/// Why a job couldn't be completed: only the encoder errors are final, the job is recovered
+/// after the Redis ones
+#[derive(Debug)]
+enum JobError {
+    Encoder(EncoderError),
+    Redis(RedisFetchError),
+}
+
+impl From<EncoderError> for JobError {
+    fn from(other: EncoderError) -> Self {
+        JobError::Encoder(other)
+    }
+}
+
+impl From<std::io::Error> for JobError {
+    fn from(other: std::io::Error) -> Self {
+        JobError::Encoder(other.into())
+    }
+}
+
+impl From<RedisFetchError> for JobError {
+    fn from(other: RedisFetchError) -> Self {
+        JobError::Redis(other)
+    }
+}
+
+impl From<redis::RedisError> for JobError {
+    fn from(other: redis::RedisError) -> Self {
+        JobError::Redis(other.into())
+    }
+}
+
+/// Stores the progress of every run of the encoder and publishes it to the viewers of the video
+async fn report_progress(
+    db: &RedisMultiplexed,
+    id: String,
+    mut rx: UnboundedReceiver<EncodingProgress>,
+) -> Result<(), RedisFetchError> {
+    let mut video_progress = VideoEncodingProgress {
+        id,
+        runs: BTreeMap::new(),
+    };
+
+    while let Some(progress) = rx.next().await {
+        let packet = WsPacket::EncodingProgress {
+            variant: progress.variant.clone(),
+            pass: progress.pass,
+            passes: progress.passes,
+            progress: progress.progress,
+            eta: progress.eta,
+        };
+
+        video_progress
+            .runs
+            .insert(progress.variant.clone(), progress);
+        video_progress.save(db).await?;
+
+        let _: () =
+            redis::Cmd::publish(&video_progress.id, &serde_json::to_string(&packet).unwrap())
+                .query_async(&mut db.get_multiplexed_tokio_connection().await?)
+                .await?;
+    }
+
+    Ok(())
+}
+
+async fn encode(db: &RedisMultiplexed, config: &Config, job: &EncodingJob) -> Result<(), JobError> {
+    // variants that were already completed by a previous run are skipped by the encoder
+    let segments = recording_segments(&config.storage_dir, &job.id)?;
+
+    let (tx, rx) = unbounded();
+    let encoder = Encoder::new(job.id.clone(), segments, config)
+        .await?
+        .with_progress(tx);
+    // the channel is closed as soon as the encoder is dropped at the end of `encode()`
+    let (result, reported) = join(encoder.encode(), report_progress(db, job.id.clone(), rx)).await;
+
+    if let Some(progress) = VideoEncodingProgress::get(db, job.id.clone()).await? {
+        progress.del(db).await?;
+    }
+    reported?;
+    let result = result?;
+
+    debug!("Encoding completed with result: {:?}", result);
+
+    if let Some(mut video) = Video::get(db, job.id.clone()).await? {
+        video.status = VideoStatus::Published {
+            timestamp: job.timestamp,
+            duration: result.duration,
+            variants: result.variants,
+            hls: result.hls,
+            dash: result.dash,
+            trickplay: Some(result.trickplay),
+            views: 0,
+        };
+        // keep the thumbnail picked by the admin, if any
+        if video.thumbnail.is_none() {
+            video.thumbnail = result.thumbnails.first().cloned();
+        }
+        video.poster = Some(result.poster);
+        video.thumbnails = result.thumbnails;
+        video.save(db).await?;
+
+        // let the viewers waiting for the encoding reload the page
+        let _: () = redis::Cmd::publish(
+            &job.id,
+            &serde_json::to_string(&WsPacket::Published {}).unwrap(),
+        )
+        .query_async(&mut db.get_multiplexed_tokio_connection().await?)
+        .await?;
+    }
+
+    // the archived live stream was only needed until now
+    let archive_dir = archive_dir(config, &job.id);
+    if archive_dir.exists() {
+        tokio::fs::remove_dir_all(archive_dir).await?;
+    }
+
+    Ok(())
+}
+
+async fn process(
+    db: &RedisMultiplexed,
+    config: &Config,
+    worker: &str,
+    id: String,
+) -> Result<(), RedisFetchError> {
+    let mut job = match EncodingJob::get(db, id.clone()).await? {
+        Some(job) => job,
+        None => return release(db, worker, &id).await,
+    };
+
+    info!("Worker `{}` encoding video `{}`", worker, job.id);
+
+    job.status = EncodingJobStatus::Running {
+        worker: worker.to_string(),
+        started_timestamp: unix_timestamp(),
+    };
+    job.save(db).await?;
+
+    job.status = match encode(db, config, &job).await {
+        Ok(()) => EncodingJobStatus::Done,
+        // the job is still in the list of the worker, and it's retried once recovered
+        Err(JobError::Redis(e)) => return Err(e),
+        Err(JobError::Encoder(e)) => {
+            error!("Encoding of video `{}` failed: {:?}", job.id, e);
+
+            let reason = format!("{:?}", e);
+            if let Some(mut video) = Video::get(db, job.id.clone()).await? {
+                match video.status {
+                    // the archived live stream can still be watched, the failure is only recorded
+                    // so that the encoding can be retried
+                    VideoStatus::Provisional { ref mut failed, .. }
+                        if archive_dir(config, &job.id).exists() =>
+                    {
+                        *failed = Some(reason.clone());
+                    }
+                    _ => {
+                        video.status = VideoStatus::Failed {
+                            reason: reason.clone(),
+                            timestamp: unix_timestamp(),
+                        };
+                    }
+                }
+                video.save(db).await?;
+            }
+
+            EncodingJobStatus::Failed { reason }
+        }
+    };
+    job.save(db).await?;
+
+    release(db, worker, &job.id).await
+}
+
+/// Encodes the jobs in the queue until it's empty
+async fn drain(
+    db: &RedisMultiplexed,
+    config: &Config,
+    worker: &str,
+) -> Result<(), RedisFetchError> {
+    while let Some(id) = claim(db, worker).await? {
+        process(db, config, worker, id).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn run_worker(
+    db: Arc<RedisMultiplexed>,
+    config: Arc<Config>,
+    worker: String,
+) -> Result<(), RedisFetchError> {
+    recover(&db, &worker).await?;
+
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = drain(&db, &config, &worker).await {
+            error!("Worker `{}` stopped draining the queue: {:?}", worker, e);
+            // put back the job that was interrupted, if any
+            recover(&db, &worker).await?;
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::path::Path;
+
+use log::{debug, info};
+
+use crate::config::Config;
+use crate::hls::{self, MediaPlaylist};
+use crate::types::LiveRenditions;
+
+/// Directory containing the archived live stream, inside the encoded files of a video
+pub const ARCHIVE_DIR: &str = "live";
+/// Master playlist of the archive, relative to the encoded files of the video
+pub const ARCHIVE_MASTER: &str = "live/master.m3u8";
+
+/// Keeps the HLS output of an ended live stream, so that it can be watched right away while the
+/// recording is encoded.
+///
+/// Returns the duration of the archived stream
+pub async fn archive(config: &Config, live: &LiveRenditions) -> Result<f32, tokio::io::Error> {
+    let archive_dir = Path::new(&config.storage_dir)
+        .join("encoded")
+        .join(&live.id)
+        .join(ARCHIVE_DIR);
+
+    let mut duration = 0.0f32;
+    for rendition in &live.renditions {
+        let source_dir = Path::new(&config.hls_dir).join(&rendition.name);
+        let target_dir = archive_dir.join(&rendition.name);
+        tokio::fs::create_dir_all(&target_dir).await?;
+
+        let text = tokio::fs::read_to_string(source_dir.join("index.m3u8")).await?;
+        let mut playlist = MediaPlaylist::parse(&text);
+
+        for segment in &playlist.segments {
+            let (source, target) = (source_dir.join(&segment.uri), target_dir.join(&segment.uri));
+            // nginx-rtmp will clean up its own copy eventually
+            if std::fs::hard_link(&source, &target).is_err() {
+                tokio::fs::copy(&source, &target).await?;
+            }
+        }
+        debug!(
+            "Archived {} segments of `{}`",
+            playlist.segments.len(),
+            rendition.name
+        );
+
+        playlist.ended = true;
+        tokio::fs::write(target_dir.join("index.m3u8"), playlist.render()).await?;
+
+        duration = duration.max(playlist.duration());
+    }
+
+    let master = hls::master_playlist(&live.renditions, |rendition| {
+        format!("{}/index.m3u8", rendition.name)
+    });
+    tokio::fs::write(archive_dir.join("master.m3u8"), master).await?;
+
+    info!("Archived live stream `{}` ({}s)", live.id, duration);
+
+    Ok(duration)
+}
@@ -1,33 +1,49 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use log::{debug, info, trace};
+use log::{debug, error, info, trace};
 
 use rocket::http::Status;
 
 use crate::config::Config;
 use crate::db::{RedisEntity, RedisMultiplexed};
-use crate::encoder::Encoder;
-use crate::monitor::NginxMonitor;
+use crate::encoder::EncodingConfiguration;
+use crate::monitor::{NginxApplication, NginxMeta, NginxMonitor};
+use crate::tasks::live_transcoder::{self, LiveTranscoders};
+use crate::tasks::{encoding_queue, live_archive};
 
-use crate::types::{Video, VideoStatus, WsPacket};
+use crate::types::{
+    LatencyMode, LiveRendition, LiveRenditions, LiveThumbnail, Video, VideoStatus, WsPacket,
+};
+
+/// Seconds after which the lock taken while ending a stream is released anyway, in case the task
+/// holding it died
+const ENDING_LOCK_EXPIRY: u64 = 60;
 
 pub async fn monitor_live_streams(
     db: Arc<RedisMultiplexed>,
     monitor: Arc<NginxMonitor>,
     config: Arc<Config>,
 ) {
+    let ladder = EncodingConfiguration::load().await.unwrap().live;
+    let transcoders = LiveTranscoders::default();
+
     let mut interval = tokio::time::interval(Duration::from_secs(5));
     loop {
         interval.tick().await;
 
+        // streams that are currently being published, with the height of their source and the
+        // latency they are transcoded for
+        let mut streams = HashMap::new();
+
         let status = monitor
             .get_newer_than(Duration::from_secs(5))
             .await
             .unwrap();
         let src_app = status.get_application("src").unwrap();
 
-        for (id, mut video) in Video::list(&db).await.unwrap() {
+        for (id, video) in Video::list(&db).await.unwrap() {
             match video.status {
                 VideoStatus::Live { .. } | VideoStatus::Scheduled { .. } => {
                     // update the viewers count
@@ -43,7 +59,9 @@ pub async fn monitor_live_streams(
             }
 
             if let VideoStatus::Live {
-                started_timestamp, ..
+                started_timestamp,
+                disconnected_timestamp,
+                ..
             } = video.status
             {
                 let live_for = SystemTime::now()
@@ -58,38 +76,169 @@ pub async fn monitor_live_streams(
                 debug!("Currently live: {} ~{:?}", id, live_for);
                 trace!("{:#?}", stream);
 
-                // clean dead streams or streams completed
-                if live_for > Duration::from_secs(60)
-                    && (stream.is_none() || stream.unwrap().bw_in == 0)
-                {
-                    // TODO: cleanup message_invoices in redis
-
-                    video.status = VideoStatus::Processing;
-                    video.save(&db).await.unwrap();
-
-                    let cloned_config = Arc::clone(&config);
-                    let cloned_db = Arc::clone(&db);
-                    tokio::spawn(async move {
-                        let encoder = Encoder::new(id, &cloned_config).await.unwrap();
-                        let result = encoder.encode().await.unwrap();
-
-                        debug!("Encoding completed with result: {:?}", result);
-
-                        video.status = VideoStatus::Published {
-                            timestamp: started_timestamp,
-                            duration: result.duration,
-                            variants: result.variants,
-                            views: 0,
-                        };
-                        video.save(&cloned_db).await.unwrap();
-                    });
+                match (stream, disconnected_timestamp) {
+                    (Some(stream), _) => {
+                        let hls_app = status.get_application(video.latency.hls_app());
+                        update_renditions(&db, hls_app, &id).await;
+
+                        // wait for nginx-rtmp to know what's being streamed
+                        if let Some(height) = live_transcoder::source_height(stream) {
+                            streams.insert(id, (height, video.latency));
+                        }
+                    }
+                    // fallback for streams whose `on_publish_done` callback was missed
+                    (None, None) if live_for > Duration::from_secs(60) => {
+                        disconnect_stream(Arc::clone(&db), Arc::clone(&config), id).await;
+                    }
+                    // the streamer didn't come back in time
+                    (None, Some(disconnected_timestamp))
+                        if unix_timestamp() >= disconnected_timestamp + config.reconnect_grace =>
+                    {
+                        finish_stream(Arc::clone(&db), Arc::clone(&config), id).await;
+                    }
+                    _ => {}
                 }
             }
         }
+
+        transcoders.reconcile(&config, &ladder, streams);
+    }
+}
+
+/// Stores the renditions of the stream that are actually being published, for the master playlist
+async fn update_renditions(db: &RedisMultiplexed, hls_app: Option<&NginxApplication>, id: &str) {
+    let prefix = format!("{}_", id);
+    let mut renditions = hls_app
+        .and_then(|app| app.live.streams.as_ref())
+        .into_iter()
+        .flatten()
+        .filter(|stream| stream.name.starts_with(&prefix))
+        .filter_map(|stream| {
+            let metas = &stream.meta.as_ref()?.metas;
+            let (width, height) = metas.iter().find_map(|meta| match meta {
+                NginxMeta::Video { width, height, .. } => Some((*width, *height)),
+                _ => None,
+            })?;
+
+            Some(LiveRendition {
+                name: stream.name.clone(),
+                width,
+                height,
+                bandwidth: stream.bw_in,
+                codecs: metas.iter().filter_map(NginxMeta::codecs).collect(),
+            })
+        })
+        .collect::<Vec<_>>();
+    renditions.sort_by_key(|rendition| rendition.height);
+
+    let renditions = LiveRenditions {
+        id: id.to_string(),
+        renditions,
+    };
+    renditions.save(db).await.unwrap();
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Marks a live video as disconnected, giving the streamer `reconnect_grace` seconds to resume it
+/// before it's finished
+pub async fn disconnect_stream(db: Arc<RedisMultiplexed>, config: Arc<Config>, id: String) {
+    if config.reconnect_grace == 0 {
+        return finish_stream(db, config, id).await;
+    }
+
+    let mut video = match Video::get(&db, id).await.unwrap() {
+        Some(video) => video,
+        None => return,
+    };
+
+    if let VideoStatus::Live {
+        ref mut disconnected_timestamp,
+        ..
+    } = video.status
+    {
+        info!("Stream {} disconnected", video.id);
+
+        *disconnected_timestamp = Some(unix_timestamp());
+        video.save(&db).await.unwrap();
+    }
+}
+
+/// Moves a live video to `Provisional`, or `Processing` if its HLS output can't be kept, and
+/// queues the encoding of its recording
+pub async fn finish_stream(db: Arc<RedisMultiplexed>, config: Arc<Config>, id: String) {
+    // the callback and the monitor may both try to end the same stream: only the one holding the
+    // lock moves it out of `Live`, the other one then finds it already `Processing`
+    let lock = format!("ending:{}", id);
+    let mut con = db.get_multiplexed_tokio_connection().await.unwrap();
+    let acquired: Option<String> = redis::cmd("SET")
+        .arg(&lock)
+        .arg(1)
+        .arg("NX")
+        .arg("EX")
+        .arg(ENDING_LOCK_EXPIRY)
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    if acquired.is_none() {
+        debug!("Stream {} is already being ended", id);
+        return;
+    }
+
+    let mut video = match Video::get(&db, id.clone()).await.unwrap() {
+        Some(video) => video,
+        None => return,
+    };
+    let started_timestamp = match video.status {
+        VideoStatus::Live {
+            started_timestamp, ..
+        } => started_timestamp,
+        _ => return,
+    };
+
+    info!("Stream {} ended", video.id);
+
+    // TODO: cleanup message_invoices in redis
+
+    video.status = VideoStatus::Processing;
+    video.save(&db).await.unwrap();
+    let _: () = redis::cmd("DEL")
+        .arg(&lock)
+        .query_async(&mut con)
+        .await
+        .unwrap();
+
+    if let Some(thumbnail) = LiveThumbnail::get(&db, video.id.clone()).await.unwrap() {
+        thumbnail.del(&db).await.unwrap();
+    }
+    if let Some(renditions) = LiveRenditions::get(&db, video.id.clone()).await.unwrap() {
+        match live_archive::archive(&config, &renditions).await {
+            Ok(duration) => {
+                video.status = VideoStatus::Provisional {
+                    timestamp: started_timestamp,
+                    duration,
+                    hls: live_archive::ARCHIVE_MASTER.into(),
+                    failed: None,
+                };
+                video.save(&db).await.unwrap();
+            }
+            Err(e) => error!("Unable to archive live stream `{}`: {:?}", video.id, e),
+        }
+
+        renditions.del(&db).await.unwrap();
     }
+
+    encoding_queue::enqueue(&db, video.id, started_timestamp)
+        .await
+        .unwrap();
 }
 
-pub fn publish_stream(db: Arc<RedisMultiplexed>, name: String) -> Status {
+pub fn publish_stream(db: Arc<RedisMultiplexed>, config: &Config, name: String) -> Status {
     info!("Published stream {}", name);
 
     match Video::sync_get(&db, name).unwrap() {
@@ -104,16 +253,78 @@ pub fn publish_stream(db: Arc<RedisMultiplexed>, name: String) -> Status {
             },
         ) => {
             video.status = VideoStatus::Live {
-                started_timestamp: SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
+                started_timestamp: unix_timestamp(),
                 viewers: 0,
+                disconnected_timestamp: None,
+                disconnected_seconds: 0,
             };
             video.sync_save(&db).unwrap();
 
             Status::Ok
         }
+        // the streamer reconnected: nginx-rtmp will record a new segment for the same session.
+        // While the original publisher is still connected, anyone else holding the key is refused
+        Some(
+            mut
+            video
+            @
+            Video {
+                status: VideoStatus::Live { .. },
+                ..
+            },
+        ) if within_grace(&video.status, config.reconnect_grace) => {
+            info!("Resuming stream {}", video.id);
+
+            if let VideoStatus::Live {
+                ref mut disconnected_timestamp,
+                ref mut disconnected_seconds,
+                ..
+            } = video.status
+            {
+                if let Some(disconnected) = disconnected_timestamp.take() {
+                    *disconnected_seconds += unix_timestamp().saturating_sub(disconnected);
+                }
+            }
+            video.sync_save(&db).unwrap();
+
+            Status::Ok
+        }
         _ => Status::NotAcceptable,
     }
 }
+
+/// Whether a disconnected streamer can still resume the stream
+fn within_grace(status: &VideoStatus, reconnect_grace: u64) -> bool {
+    match status {
+        VideoStatus::Live {
+            disconnected_timestamp: Some(disconnected),
+            ..
+        } => unix_timestamp() < disconnected + reconnect_grace,
+        _ => false,
+    }
+}
+
+/// Creates a new video that is immediately live, for streams started without scheduling them
+pub fn allocate_stream(db: Arc<RedisMultiplexed>) -> Video {
+    let video = Video {
+        id: Video::generate_id(),
+        title: "Untitled live stream".into(),
+        description: String::new(),
+        status: VideoStatus::Live {
+            started_timestamp: unix_timestamp(),
+            viewers: 0,
+            disconnected_timestamp: None,
+            disconnected_seconds: 0,
+        },
+        thumbnail: None,
+        poster: None,
+        thumbnails: vec![],
+        latency: LatencyMode::default(),
+        dvr_window: None,
+    };
+    video.sync_save(&db).unwrap();
+
+    info!("Allocated new live stream {}", video.id);
+
+    video
+}
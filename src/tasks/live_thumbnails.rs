@@ -0,0 +1,113 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info, trace};
+
+use tokio::process::Command;
+
+use crate::config::Config;
+use crate::db::{RedisEntity, RedisMultiplexed};
+use crate::encoder::EncodingConfiguration;
+use crate::types::{LiveThumbnail, Video, VideoStatus};
+
+/// File the latest frame of a live stream is written to, inside the encoded files of the video
+pub const LIVE_THUMBNAIL: &str = "live.jpg";
+
+/// Seconds after which a capture is abandoned, e.g. when the playlist stalls
+const CAPTURE_TIMEOUT: u64 = 30;
+
+pub async fn capture_live_thumbnails(db: Arc<RedisMultiplexed>, config: Arc<Config>) {
+    if config.live_thumbnail_interval == 0 {
+        info!("Live thumbnails disabled");
+        return;
+    }
+
+    // the frames are taken from the smallest rendition, which is enough for a preview and is
+    // always transcoded, whatever the height of the source
+    let rendition = match EncodingConfiguration::load()
+        .await
+        .unwrap()
+        .live
+        .values()
+        .map(|r| r.height)
+        .min()
+    {
+        Some(height) => height,
+        None => {
+            info!("Live thumbnails disabled, there are no live renditions");
+            return;
+        }
+    };
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.live_thumbnail_interval));
+    loop {
+        interval.tick().await;
+
+        for (id, video) in Video::list(&db).await.unwrap() {
+            match video.status {
+                VideoStatus::Live {
+                    disconnected_timestamp: None,
+                    ..
+                } => {}
+                _ => continue,
+            }
+
+            // the stream may have just started, there'll be another chance at the next tick
+            if let Err(e) = capture(&config, &id, rendition).await {
+                debug!("Unable to capture live thumbnail for `{}`: {:?}", id, e);
+                continue;
+            }
+
+            let thumbnail = LiveThumbnail {
+                id,
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+            };
+            thumbnail.save(&db).await.unwrap();
+        }
+    }
+}
+
+/// Grabs the most recent frame of the live HLS output
+async fn capture(config: &Config, id: &str, rendition: usize) -> Result<(), tokio::io::Error> {
+    let playlist = Path::new(&config.hls_dir)
+        .join(format!("{}_{}", id, rendition))
+        .join("index.m3u8");
+    if !playlist.exists() {
+        return Err(tokio::io::ErrorKind::NotFound.into());
+    }
+
+    let output_dir = Path::new(&config.storage_dir).join("encoded").join(id);
+    tokio::fs::create_dir_all(&output_dir).await?;
+    // written somewhere else first, so that the page never loads a half-written image
+    let partial = output_dir
+        .join(LIVE_THUMBNAIL)
+        .with_extension("partial.jpg");
+
+    let mut cmd = Command::new("ffmpeg");
+    cmd.kill_on_drop(true)
+        .arg("-y")
+        // start from the last fragment
+        .args(&["-live_start_index", "-1"])
+        .args(&["-i", playlist.to_str().unwrap()])
+        .args(&["-frames:v", "1"])
+        .args(&["-q:v", "3"])
+        .args(&[partial.to_str().unwrap()]);
+    trace!("{:?}", cmd);
+
+    // the process is killed when the timeout drops it
+    let output = tokio::time::timeout(Duration::from_secs(CAPTURE_TIMEOUT), cmd.output())
+        .await
+        .map_err(|_| tokio::io::Error::from(tokio::io::ErrorKind::TimedOut))??;
+    if !output.status.success() {
+        return Err(tokio::io::Error::new(
+            tokio::io::ErrorKind::Other,
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ));
+    }
+
+    tokio::fs::rename(&partial, output_dir.join(LIVE_THUMBNAIL)).await
+}
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, trace};
+
+use futures::future::{abortable, AbortHandle};
+
+use tokio::process::Command;
+
+use crate::config::Config;
+use crate::encoder::LiveVariant;
+use crate::monitor::{NginxMeta, NginxStream};
+use crate::types::LatencyMode;
+
+/// Wait before restarting a crashed transcoder, doubled after every crash
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A transcoder that ran for this long is considered healthy, and resets the backoff
+const HEALTHY_AFTER: Duration = Duration::from_secs(60);
+
+/// One supervised ffmpeg process for every live stream, publishing its renditions to the `hls`
+/// application
+#[derive(Debug, Default)]
+pub struct LiveTranscoders {
+    running: Mutex<HashMap<String, AbortHandle>>,
+}
+
+impl LiveTranscoders {
+    /// Starts transcoding the new `streams`, given as id, height of the source and latency mode,
+    /// and stops the transcoders of the streams that aren't there anymore
+    pub fn reconcile(
+        &self,
+        config: &Arc<Config>,
+        ladder: &HashMap<String, LiveVariant>,
+        streams: HashMap<String, (usize, LatencyMode)>,
+    ) {
+        let mut running = self.running.lock().unwrap();
+
+        running.retain(|id, handle| {
+            if streams.contains_key(id) {
+                return true;
+            }
+
+            info!("Stopping live transcoder for `{}`", id);
+            // dropping the child process kills it
+            handle.abort();
+            false
+        });
+
+        for (id, (source_height, latency)) in streams {
+            if running.contains_key(&id) {
+                continue;
+            }
+
+            let renditions = renditions(ladder, source_height);
+            info!(
+                "Starting live transcoder for `{}` ({}p, {:?} latency): {:?}",
+                id,
+                source_height,
+                latency,
+                renditions.iter().map(|r| r.height).collect::<Vec<_>>()
+            );
+
+            let (future, handle) = abortable(supervise(
+                Arc::clone(config),
+                id.clone(),
+                renditions,
+                latency,
+            ));
+            tokio::spawn(future);
+            running.insert(id, handle);
+        }
+    }
+}
+
+/// Height of the video published by the streamer
+pub fn source_height(stream: &NginxStream) -> Option<usize> {
+    stream
+        .meta
+        .as_ref()?
+        .metas
+        .iter()
+        .find_map(|meta| match meta {
+            NginxMeta::Video { height, .. } => Some(*height),
+            _ => None,
+        })
+}
+
+/// Renditions of the ladder that aren't taller than the source, from the smallest
+fn renditions(ladder: &HashMap<String, LiveVariant>, source_height: usize) -> Vec<LiveVariant> {
+    let mut renditions = ladder.values().cloned().collect::<Vec<_>>();
+    renditions.sort_by_key(|r| r.height);
+
+    // always keep at least the smallest rendition, or there would be nothing to watch
+    let fitting = renditions
+        .iter()
+        .filter(|r| r.height <= source_height)
+        .count()
+        .max(1);
+    renditions.truncate(fitting);
+
+    renditions
+}
+
+/// Keeps the transcoder running until it's aborted, restarting it with an exponential backoff
+async fn supervise(
+    config: Arc<Config>,
+    id: String,
+    renditions: Vec<LiveVariant>,
+    latency: LatencyMode,
+) {
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        let started = Instant::now();
+        match transcode(&config, &id, &renditions, latency).await {
+            Ok(status) => error!("Live transcoder for `{}` exited with {}", id, status),
+            Err(e) => error!("Unable to run live transcoder for `{}`: {:?}", id, e),
+        }
+
+        if started.elapsed() >= HEALTHY_AFTER {
+            backoff = MIN_BACKOFF;
+        }
+
+        debug!("Restarting live transcoder for `{}` in {:?}", id, backoff);
+        tokio::time::delay_for(backoff).await;
+        backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+    }
+}
+
+async fn transcode(
+    config: &Config,
+    id: &str,
+    renditions: &[LiveVariant],
+    latency: LatencyMode,
+) -> Result<std::process::ExitStatus, tokio::io::Error> {
+    let logs_dir = Path::new(&config.storage_dir)
+        .join("encoded")
+        .join(id)
+        .join("logs");
+    std::fs::create_dir_all(&logs_dir)?;
+    // kept across restarts, so that the reason of every crash can be found there
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(logs_dir.join("live.log"))?;
+
+    let mut cmd = Command::new("ffmpeg");
+    cmd.kill_on_drop(true)
+        .arg("-nostats")
+        .args(&["-loglevel", "warning"])
+        .args(&["-i", &format!("{}/src/{}", config.rtmp_url, id)]);
+
+    for rendition in renditions {
+        cmd.args(&["-map", "0:v"])
+            .args(&["-map", "0:a?"])
+            .args(&["-vf", &format!("scale=-2:{}", rendition.height)])
+            .args(&["-c:v", "libx264"])
+            .args(&["-preset", &rendition.preset])
+            .args(&["-b:v", &format!("{}K", rendition.bitrate)])
+            // nginx-rtmp can only cut the fragments on keyframes
+            .args(&[
+                "-force_key_frames",
+                &format!("expr:gte(t,n_forced*{})", latency.fragment()),
+            ]);
+        if latency == LatencyMode::Low {
+            cmd.args(&["-tune", "zerolatency"]);
+        }
+        cmd.args(&["-c:a", "aac"])
+            .args(&["-ar", "44100"])
+            .args(&["-ac", &format!("{}", rendition.audio_channels)])
+            .args(&["-f", "flv"])
+            .arg(format!(
+                "{}/{}/{}_{}",
+                config.rtmp_url,
+                latency.hls_app(),
+                id,
+                rendition.height
+            ));
+    }
+    trace!("{:?}", cmd);
+
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::from(log))
+        .spawn()?
+        .await
+}
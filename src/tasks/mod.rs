@@ -1 +1,5 @@
+pub mod encoding_queue;
+pub mod live_archive;
 pub mod live_monitor;
+pub mod live_thumbnails;
+pub mod live_transcoder;
@@ -1,3 +1,8 @@
+use std::collections::BTreeMap;
+
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
 use serde::{Deserialize, Serialize};
 
 use crate::db::RedisEntity;
@@ -6,12 +11,19 @@ use crate::db::RedisEntity;
 pub enum WsPacket {
     Join {
         room: String,
+        /// One of the `moderators` tokens in the config
+        #[serde(default)]
+        token: Option<String>,
     },
     AssignedUsername {
         username: String,
+        #[serde(default)]
+        moderator: bool,
     },
 
     ServerMessage {
+        #[serde(default)]
+        id: String,
         from: String,
         message: String,
         extra: Option<MessageExtra>,
@@ -20,6 +32,45 @@ pub enum WsPacket {
         message: String,
     },
 
+    // sent by the moderators
+    DeleteMessage {
+        id: String,
+    },
+    TimeoutUser {
+        username: String,
+        /// In seconds
+        duration: u64,
+    },
+    BanUser {
+        username: String,
+    },
+    SetSlowMode {
+        /// Seconds between the messages of every user, 0 to disable it
+        interval: u64,
+    },
+
+    MessageDeleted {
+        id: String,
+    },
+    SlowMode {
+        interval: u64,
+    },
+
+    /// A request carrying a `request_id` was handled
+    Ack {
+        request_id: String,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+        /// Of the request that caused the error, if any
+        #[serde(default)]
+        request_id: Option<String>,
+        /// Seconds to wait before trying again
+        #[serde(default)]
+        retry_after: Option<u64>,
+    },
+
     GetInvoice {
         amount: u64,
         message: String,
@@ -31,15 +82,71 @@ pub enum WsPacket {
     UpdateViewers {
         viewers: usize,
     },
+
+    EncodingProgress {
+        variant: String,
+        pass: usize,
+        passes: usize,
+        progress: f32,
+        eta: Option<u64>,
+    },
+    /// The encoding is over, and the final version of the video can be watched
+    Published {},
+}
+
+/// A packet sent by a client, with an optional id echoed in the `Ack` or `Error` answering it
+#[derive(Deserialize, Debug)]
+pub struct WsRequest {
+    #[serde(flatten)]
+    pub packet: WsPacket,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+/// Why a request was refused
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidPacket,
+    RoomNotFound,
+    /// The video doesn't have a chat anymore
+    RoomClosed,
+    AlreadyJoined,
+    NotJoined,
+    /// Only updates are sent to the chat of the videos that aren't live anymore
+    ReadOnly,
+    EmptyMessage,
+    Unauthorized,
+    /// The moderated user isn't in the chat anymore
+    UserNotFound,
+    Banned,
+    TimedOut,
+    SlowMode,
+    RateLimited,
+    Internal,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MessageExtra {
     pub amount: u64,
+    /// When the message was boosted, to tell how much is left of the badge
     pub timestamp: u64,
+    /// How long the badge is shown, in seconds
     pub duration: u64,
 }
 
+/// A chat message as kept in the log of the video, to replay it along the recording
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatLogEntry {
+    #[serde(default)]
+    pub id: String,
+    /// Seconds since the start of the stream, 0 for the messages sent while it was scheduled
+    pub offset: u64,
+    pub from: String,
+    pub message: String,
+    pub extra: Option<MessageExtra>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum VideoStatus {
     Scheduled {
@@ -48,16 +155,45 @@ pub enum VideoStatus {
     Live {
         started_timestamp: u64,
         viewers: usize,
+        /// Set while the streamer is disconnected, until they resume or the grace window expires
+        #[serde(default)]
+        disconnected_timestamp: Option<u64>,
+        /// Seconds the streamer was disconnected before resuming, missing from the recording
+        #[serde(default)]
+        disconnected_seconds: u64,
     },
     Upload {
         timestamp: u64,
     },
     Processing,
+    /// The live stream can already be watched from its HLS output, while it's being encoded
+    Provisional {
+        timestamp: u64,
+        duration: f32,
+        /// HLS master playlist, relative to the encoded files of the video
+        hls: String,
+        /// Reason of the failure of the final encoding, which can then be retried
+        #[serde(default)]
+        failed: Option<String>,
+    },
+    Failed {
+        reason: String,
+        timestamp: u64,
+    },
     Published {
         timestamp: u64,
         duration: f32,
         views: usize,
         variants: Vec<(usize, String, String)>,
+        /// HLS master playlist, relative to the encoded files of the video
+        #[serde(default)]
+        hls: Option<String>,
+        /// DASH manifest, relative to the encoded files of the video
+        #[serde(default)]
+        dash: Option<String>,
+        /// WebVTT index of the seek preview sprites, relative to the encoded files of the video
+        #[serde(default)]
+        trickplay: Option<String>,
     },
 }
 
@@ -67,6 +203,60 @@ pub struct Video {
     pub title: String,
     pub description: String,
     pub status: VideoStatus,
+    /// Image shown in the previews, inside the encoded files of the video
+    #[serde(default)]
+    pub thumbnail: Option<String>,
+    /// Large frame shown by the player before playback starts
+    #[serde(default)]
+    pub poster: Option<String>,
+    /// Thumbnails extracted by the encoder, which can be picked as `thumbnail`
+    #[serde(default)]
+    pub thumbnails: Vec<String>,
+    #[serde(default)]
+    pub latency: LatencyMode,
+    /// Seconds of the live stream that can be rewound, instead of the `dvr_window` in the config
+    #[serde(default)]
+    pub dvr_window: Option<u64>,
+}
+
+/// Trade-off between the delay of the live stream and how smooth it plays
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LatencyMode {
+    Normal,
+    /// Short fragments, with keyframes as frequent and a player buffering less. nginx-rtmp can't
+    /// write the partial segments of LL-HLS, so this is as low as it gets
+    Low,
+}
+
+impl Default for LatencyMode {
+    fn default() -> Self {
+        LatencyMode::Normal
+    }
+}
+
+impl LatencyMode {
+    /// nginx-rtmp application the renditions are published to
+    pub fn hls_app(&self) -> &'static str {
+        match self {
+            LatencyMode::Normal => "hls",
+            LatencyMode::Low => "hls_ll",
+        }
+    }
+
+    /// Length of the fragments in seconds, the same as `hls_fragment` in the `hls_app`
+    pub fn fragment(&self) -> u64 {
+        match self {
+            LatencyMode::Normal => 4,
+            LatencyMode::Low => 1,
+        }
+    }
+}
+
+impl Video {
+    pub fn generate_id() -> String {
+        thread_rng().sample_iter(&Alphanumeric).take(11).collect()
+    }
 }
 
 impl RedisEntity for Video {
@@ -81,6 +271,163 @@ impl RedisEntity for Video {
     }
 }
 
+/// Secret name used by the streamer to publish, mapped to the public id of the video.
+///
+/// Keys without a video are channel keys, which allocate a new video every time they are used
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StreamKey {
+    pub key: String,
+    pub video: Option<String>,
+    /// Last video allocated by a channel key, resumed if the streamer reconnects in time
+    #[serde(default)]
+    pub live_video: Option<String>,
+}
+
+impl StreamKey {
+    pub fn generate(video: Option<&str>) -> Self {
+        StreamKey {
+            key: thread_rng().sample_iter(&Alphanumeric).take(32).collect(),
+            video: video.map(str::to_string),
+            live_video: None,
+        }
+    }
+}
+
+impl RedisEntity for StreamKey {
+    type Id = String;
+
+    fn key() -> &'static str {
+        "stream_keys"
+    }
+
+    fn id(&self) -> &String {
+        &self.key
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum EncodingJobStatus {
+    Queued,
+    Running {
+        worker: String,
+        started_timestamp: u64,
+    },
+    Done,
+    Failed {
+        reason: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncodingJob {
+    /// Id of the video to encode
+    pub id: String,
+    /// When the stream started, used as the publishing date of the video
+    pub timestamp: u64,
+    pub status: EncodingJobStatus,
+}
+
+impl RedisEntity for EncodingJob {
+    type Id = String;
+
+    fn key() -> &'static str {
+        "encoding_jobs"
+    }
+
+    fn id(&self) -> &String {
+        &self.id
+    }
+}
+
+/// Progress of an ffmpeg run encoding a group of variants of a video
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncodingProgress {
+    /// Id of the video being encoded
+    pub id: String,
+    pub variant: String,
+    pub pass: usize,
+    pub passes: usize,
+    /// Between 0 and 1, for the current pass
+    pub progress: f32,
+    /// Estimated seconds left for the current pass
+    pub eta: Option<u64>,
+}
+
+/// Progress of every ffmpeg run encoding a video, as the groups of variants can be encoded at the
+/// same time
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VideoEncodingProgress {
+    /// Id of the video being encoded
+    pub id: String,
+    /// Keyed by the variant, or group of variants, encoded by the run
+    pub runs: BTreeMap<String, EncodingProgress>,
+}
+
+impl RedisEntity for VideoEncodingProgress {
+    type Id = String;
+
+    fn key() -> &'static str {
+        "encoding_progress"
+    }
+
+    fn id(&self) -> &String {
+        &self.id
+    }
+}
+
+/// Last frame captured from a live stream
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LiveThumbnail {
+    /// Id of the live video
+    pub id: String,
+    /// When the frame was captured, used to bust the browser cache
+    pub timestamp: u64,
+}
+
+impl RedisEntity for LiveThumbnail {
+    type Id = String;
+
+    fn key() -> &'static str {
+        "live_thumbnails"
+    }
+
+    fn id(&self) -> &String {
+        &self.id
+    }
+}
+
+/// A rendition of a live stream, as published to the `hls` application
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LiveRendition {
+    /// Name of the stream in the `hls` application, e.g. `<id>_480`
+    pub name: String,
+    pub width: usize,
+    pub height: usize,
+    /// In bits per second
+    pub bandwidth: usize,
+    pub codecs: Vec<String>,
+}
+
+/// Renditions currently being transcoded for a live video, from the smallest
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LiveRenditions {
+    /// Id of the live video
+    pub id: String,
+    pub renditions: Vec<LiveRendition>,
+}
+
+impl RedisEntity for LiveRenditions {
+    type Id = String;
+
+    fn key() -> &'static str {
+        "live_renditions"
+    }
+
+    fn id(&self) -> &String {
+        &self.id
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BoostMessageInvoice {
     pub id: String,
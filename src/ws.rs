@@ -1,117 +1,378 @@
+use std::collections::HashSet;
 use std::error;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 
 use rand::Rng;
 
-use log::{debug, info, trace};
+use log::{debug, error, info, trace};
 
 use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
-use futures::{SinkExt, StreamExt};
+use futures::{future, SinkExt, StreamExt};
 
 use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::handshake::server::{
+    ErrorResponse, Request as HandshakeRequest, Response,
+};
 use tokio_tungstenite::tungstenite::protocol::Message;
 
 use btcpay::*;
 
+use redis::aio::MultiplexedConnection;
+
+use crate::chat;
 use crate::config::Config;
 use crate::db::{RedisEntity, RedisMultiplexed};
-use crate::types::{BoostMessageInvoice, Video, VideoStatus, WsPacket};
+use crate::moderation;
+use crate::ratelimit::{RateLimiter, Request};
+use crate::types::{BoostMessageInvoice, ErrorCode, Video, VideoStatus, WsPacket, WsRequest};
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 struct State {
+    username: String,
+    ip: String,
     room: Option<String>,
+    /// Rooms of videos that aren't live anymore only receive updates
+    read_only: bool,
+    /// Joined with one of the `moderators` tokens, exempt from the slow mode
+    moderator: bool,
 }
 
 #[derive(Debug)]
 enum Action {
-    None,
     Subscribe(String),
     Broadcast(String),
     CreateInvoice(u64, String),
     // CheckInvoice(String, String),
+    DeleteMessage(String),
+    Timeout(String, u64),
+    Ban(String),
+    SlowMode(u64),
 }
 
 impl State {
+    fn new(username: String, ip: String) -> Self {
+        State {
+            username,
+            ip,
+            room: None,
+            read_only: false,
+            moderator: false,
+        }
+    }
+
+    fn room(&self) -> Result<&String, MyError> {
+        self.room
+            .as_ref()
+            .ok_or_else(|| MyError::client(ErrorCode::NotJoined, "Join a room first"))
+    }
+
+    /// Refuses the messages of the users who have been timed out, and disconnects the banned ones
+    async fn check_muted(&self, connection: &mut MultiplexedConnection) -> Result<(), MyError> {
+        if self.moderator {
+            return Ok(());
+        }
+        if moderation::is_banned(connection, &self.ip).await? {
+            return Err(MyError::fatal(
+                ErrorCode::Banned,
+                "You are banned from the chat",
+            ));
+        }
+        if moderation::is_timed_out(connection, &self.ip).await? {
+            return Err(MyError::client(
+                ErrorCode::TimedOut,
+                "You have been timed out by a moderator",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Refuses the packets that can't be applied in the current state, before looking anything up
+    fn validate(&self, msg: &WsPacket, config: &Config) -> Result<(), MyError> {
+        if let WsPacket::Join { token, .. } = msg {
+            if self.room.is_some() {
+                return Err(MyError::client(
+                    ErrorCode::AlreadyJoined,
+                    "Already joined a room",
+                ));
+            }
+            if let Some(token) = token {
+                moderator_name(config, token).ok_or_else(|| {
+                    MyError::client(ErrorCode::Unauthorized, "Invalid moderator token")
+                })?;
+            }
+
+            return Ok(());
+        }
+
+        match msg {
+            WsPacket::ClientMessage { .. }
+            | WsPacket::GetInvoice { .. }
+            | WsPacket::DeleteMessage { .. }
+            | WsPacket::TimeoutUser { .. }
+            | WsPacket::BanUser { .. }
+            | WsPacket::SetSlowMode { .. } => {}
+            _ => {
+                return Err(MyError::client(
+                    ErrorCode::InvalidPacket,
+                    "This packet can't be sent by clients",
+                ))
+            }
+        }
+
+        self.room()?;
+        if self.read_only {
+            return Err(MyError::client(
+                ErrorCode::ReadOnly,
+                "This video isn't live anymore",
+            ));
+        }
+
+        match msg {
+            WsPacket::ClientMessage { message } if message.is_empty() => {
+                Err(MyError::client(ErrorCode::EmptyMessage, "Empty message"))
+            }
+            WsPacket::DeleteMessage { .. }
+            | WsPacket::TimeoutUser { .. }
+            | WsPacket::BanUser { .. }
+            | WsPacket::SetSlowMode { .. }
+                if !self.moderator =>
+            {
+                Err(MyError::client(
+                    ErrorCode::Unauthorized,
+                    "Only moderators can do that",
+                ))
+            }
+            WsPacket::TimeoutUser { duration: 0, .. } => Err(MyError::client(
+                ErrorCode::InvalidPacket,
+                "Timeouts last at least a second",
+            )),
+            _ => Ok(()),
+        }
+    }
+
     async fn apply(
-        mut self,
+        &mut self,
         msg: WsPacket,
-        db: Arc<RedisMultiplexed>,
-    ) -> Result<(Self, Action), MyError> {
+        db: &RedisMultiplexed,
+        connection: &mut MultiplexedConnection,
+        config: &Config,
+    ) -> Result<Action, MyError> {
         debug!("State: {:?} applying: {:?}", self, msg);
 
-        match (&mut self.room, &msg) {
-            (ref mut self_room @ None, WsPacket::Join { room }) => {
-                match Video::get(&db, room.clone()).await? {
-                    Some(Video {
-                        status: VideoStatus::Live { .. },
-                        ..
-                    })
-                    | Some(Video {
-                        status: VideoStatus::Scheduled { .. },
-                        ..
-                    }) => {
-                        **self_room = Some(room.clone());
-                        return Ok((self, Action::Subscribe(room.clone())));
+        self.validate(&msg, config)?;
+
+        match msg {
+            WsPacket::Join { room, token } => {
+                let moderator = token.and_then(|token| moderator_name(config, &token));
+                if moderator.is_none() && moderation::is_banned(connection, &self.ip).await? {
+                    return Err(MyError::fatal(
+                        ErrorCode::Banned,
+                        "You are banned from the chat",
+                    ));
+                }
+
+                let video = Video::get(db, room.clone())
+                    .await?
+                    .ok_or_else(|| MyError::client(ErrorCode::RoomNotFound, "No such video"))?;
+                match video.status {
+                    VideoStatus::Live { .. } | VideoStatus::Scheduled { .. } => {}
+                    VideoStatus::Processing | VideoStatus::Provisional { .. } => {
+                        self.read_only = true
                     }
-                    _ => return Err(MyError::empty()),
+                    _ => {
+                        return Err(MyError::client(
+                            ErrorCode::RoomClosed,
+                            "The chat of this video is closed",
+                        ))
+                    }
+                }
+
+                if let Some(name) = moderator {
+                    self.username = name;
+                    self.moderator = true;
                 }
+                self.room = Some(room.clone());
+                Ok(Action::Subscribe(room))
             }
-            (None, _) => return Err(MyError::empty()),
-            _ => {}
-        }
+            WsPacket::ClientMessage { message } => {
+                self.check_muted(connection).await?;
+
+                let room = self.room()?.clone();
+                let interval = moderation::slow_mode(connection, &room).await?;
+                if !self.moderator && interval > 0 {
+                    if let Some(wait) =
+                        moderation::slow_mode_wait(connection, &room, &self.ip, interval).await?
+                    {
+                        return Err(MyError::client(ErrorCode::SlowMode, "Slow mode is on")
+                            .retry_after(wait));
+                    }
+                }
 
-        match msg {
-            WsPacket::ClientMessage { message } if message.len() > 0 => {
-                return Ok((self, Action::Broadcast(message)));
+                Ok(Action::Broadcast(message))
             }
             WsPacket::GetInvoice { amount, message } => {
-                return Ok((self, Action::CreateInvoice(amount, message)));
+                self.check_muted(connection).await?;
+
+                Ok(Action::CreateInvoice(amount, message))
             }
-            _ => {}
+            WsPacket::DeleteMessage { id } => Ok(Action::DeleteMessage(id)),
+            WsPacket::TimeoutUser { username, duration } => Ok(Action::Timeout(username, duration)),
+            WsPacket::BanUser { username } => Ok(Action::Ban(username)),
+            WsPacket::SetSlowMode { interval } => Ok(Action::SlowMode(interval)),
+            _ => unreachable!("refused by `validate()`"),
         }
-
-        Err(MyError::empty())
     }
 }
 
+/// Name of the moderator joining with `token`, if it's one of theirs
+fn moderator_name(config: &Config, token: &str) -> Option<String> {
+    config
+        .moderators
+        .iter()
+        .find(|(_, expected)| !expected.is_empty() && *expected == token)
+        .map(|(name, _)| name.clone())
+}
+
 #[derive(Debug)]
-struct MyError {
-    err: Box<dyn Send + std::fmt::Debug>,
+enum MyError {
+    /// Caused by the client, which is told about it. Unless `fatal`, the connection stays open
+    Client {
+        code: ErrorCode,
+        message: String,
+        retry_after: Option<u64>,
+        fatal: bool,
+    },
+    /// The connection is gone, nothing else can be sent
+    Disconnected,
+    /// Caused by the backends, the client only gets an `Internal` error and can keep going
+    Other(Box<dyn Send + std::fmt::Debug>),
 }
 
 impl MyError {
-    fn empty() -> Self {
-        MyError { err: Box::new(()) }
+    fn client<S: Into<String>>(code: ErrorCode, message: S) -> Self {
+        MyError::Client {
+            code,
+            message: message.into(),
+            retry_after: None,
+            fatal: false,
+        }
+    }
+
+    fn fatal<S: Into<String>>(code: ErrorCode, message: S) -> Self {
+        MyError::Client {
+            code,
+            message: message.into(),
+            retry_after: None,
+            fatal: true,
+        }
+    }
+
+    fn retry_after(mut self, seconds: u64) -> Self {
+        if let MyError::Client {
+            ref mut retry_after,
+            ..
+        } = self
+        {
+            *retry_after = Some(seconds);
+        }
+
+        self
+    }
+}
+
+/// The packet answering a request, if any
+fn response(result: &Result<(), MyError>, request_id: Option<String>) -> Option<WsPacket> {
+    match result {
+        Ok(()) => request_id.map(|request_id| WsPacket::Ack { request_id }),
+        Err(MyError::Client {
+            code,
+            message,
+            retry_after,
+            ..
+        }) => Some(WsPacket::Error {
+            code: *code,
+            message: message.clone(),
+            request_id,
+            retry_after: *retry_after,
+        }),
+        Err(MyError::Disconnected) => None,
+        Err(MyError::Other(_)) => Some(WsPacket::Error {
+            code: ErrorCode::Internal,
+            message: "Internal error".to_string(),
+            request_id,
+            retry_after: None,
+        }),
     }
 }
 
+/// Queues a packet for the client, failing only once the connection is gone
+async fn send(outgoing: &mut UnboundedSender<Message>, packet: &WsPacket) -> Result<(), MyError> {
+    outgoing
+        .send(Message::Text(serde_json::to_string(packet)?))
+        .await
+        .map_err(|_| MyError::Disconnected)
+}
+
 impl<T: 'static + error::Error + Send> From<T> for MyError {
     fn from(other: T) -> Self {
-        MyError {
-            err: Box::new(other),
-        }
+        MyError::Other(Box::new(other))
     }
 }
 
+/// Address of the client, as forwarded by the proxy in front of the server
+fn forwarded_ip(request: &HandshakeRequest) -> Option<IpAddr> {
+    let headers = request.headers();
+
+    headers
+        .get("X-Real-IP")
+        .or_else(|| headers.get("X-Forwarded-For"))
+        .and_then(|value| value.to_str().ok())
+        // every proxy appends the address it got the request from, the last one is ours
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse().ok())
+}
+
 async fn handle_connection(
     db: Arc<RedisMultiplexed>,
     btcpay_client: Arc<BTCPayClient>,
     config: Arc<Config>,
+    limiter: Arc<RateLimiter>,
     raw_stream: TcpStream,
     addr: SocketAddr,
 ) -> Result<(), MyError> {
-    let id = format!("Anon{}", rand::thread_rng().gen::<u16>());
-    let mut state = State::default();
-
-    debug!("Incoming TCP connection from: {}. ID: {}", addr, id);
-
-    let ws_stream = tokio_tungstenite::accept_async(raw_stream)
-        .await
-        .expect("Error during the websocket handshake occurred");
+    debug!("Incoming TCP connection from: {}", addr);
+
+    let mut forwarded = None;
+    let ws_stream = tokio_tungstenite::accept_hdr_async(
+        raw_stream,
+        |request: &HandshakeRequest, response: Response| -> Result<Response, ErrorResponse> {
+            forwarded = forwarded_ip(request);
+            Ok(response)
+        },
+    )
+    .await
+    .expect("Error during the websocket handshake occurred");
     debug!("WebSocket connection established: {}", addr);
 
+    // the headers can only be trusted when they are set by nginx
+    let ip = match forwarded {
+        Some(ip) if addr.ip().is_loopback() => ip,
+        _ => addr.ip(),
+    };
     let mut connection = db.get_multiplexed_tokio_connection().await?;
+    // lets the moderators ban the IP of the user, knowing only their username
+    let id = loop {
+        let id = format!("Anon{}", rand::thread_rng().gen::<u32>());
+        if moderation::register(&mut connection, &id, &ip.to_string()).await? {
+            break id;
+        }
+    };
+    debug!("Connection from {} is {}", addr, id);
+
+    let mut state = State::new(id.clone(), ip.to_string());
+    let mut limits = limiter.connection(ip);
 
     let (outgoing, mut incoming) = ws_stream.split();
     let (unbounded_tx, unbounded_rx) = unbounded::<Message>();
@@ -120,68 +381,206 @@ async fn handle_connection(
 
     let broadcast_incoming = async {
         while let Some(Ok(msg)) = incoming.next().await {
-            trace!("Received a message from {}: {}", addr, msg.to_text()?);
-
-            let msg: WsPacket = serde_json::from_str(msg.to_text()?)?;
-            let resp = state.apply(msg, db.clone()).await?;
-            state = resp.0;
-
-            match resp.1 {
-                Action::None => {}
-                Action::Subscribe(room) => {
-                    let rx = db.subscribe(&id, &room);
-
-                    let packet = WsPacket::AssignedUsername {
-                        username: id.clone(),
-                    };
-                    outgoing
-                        .send(Message::Text(serde_json::to_string(&packet)?))
-                        .await?;
-
-                    let receive_from_others = rx
-                        .map(|msg| Ok(Message::Text(msg)))
-                        .forward(outgoing.clone());
-                    tokio::spawn(receive_from_others);
+            if msg.is_close() {
+                break;
+            }
+            // pings are answered by tungstenite
+            if !msg.is_text() && !msg.is_binary() {
+                continue;
+            }
+            trace!("Received a message from {}: {:?}", addr, msg);
+
+            // echoed even if the rest of the request is invalid
+            let mut request_id = None;
+            let result = async {
+                let value: serde_json::Value = msg
+                    .to_text()
+                    .ok()
+                    .and_then(|text| serde_json::from_str(text).ok())
+                    .ok_or_else(|| MyError::client(ErrorCode::InvalidPacket, "Malformed JSON"))?;
+                request_id = value
+                    .get("request_id")
+                    .and_then(|id| id.as_str())
+                    .map(String::from);
+                let request: WsRequest = serde_json::from_value(value).map_err(|e| {
+                    MyError::client(ErrorCode::InvalidPacket, format!("Invalid packet: {}", e))
+                })?;
+
+                let kind = match request.packet {
+                    WsPacket::ClientMessage { .. } => Some(Request::Message),
+                    WsPacket::GetInvoice { .. } => Some(Request::Invoice),
+                    _ => None,
+                };
+                if let Some(Err(retry_after)) = kind.map(|kind| limits.take(kind)) {
+                    // rounded up, retrying earlier would fail again
+                    let retry_after =
+                        retry_after.as_secs() + (retry_after.subsec_nanos() > 0) as u64;
+
+                    if limits.violation() {
+                        info!("Disconnecting {} for going over the rate limits", addr);
+                        return Err(MyError::fatal(
+                            ErrorCode::RateLimited,
+                            "Too many requests, disconnecting",
+                        )
+                        .retry_after(retry_after));
+                    }
+                    return Err(MyError::client(ErrorCode::RateLimited, "Too many requests")
+                        .retry_after(retry_after));
                 }
-                Action::Broadcast(message) => {
-                    let packet = WsPacket::ServerMessage {
-                        from: id.clone(),
-                        message,
-                        extra: None,
-                    };
-                    let _: () = redis::Cmd::publish(
-                        state.room.as_ref().ok_or(MyError::empty())?,
-                        &serde_json::to_string(&packet)?,
-                    )
-                    .query_async(&mut connection)
-                    .await?;
+
+                match state
+                    .apply(request.packet, &db, &mut connection, &config)
+                    .await?
+                {
+                    Action::Subscribe(room) => {
+                        let rx = db.subscribe(&id, &room);
+
+                        let packet = WsPacket::AssignedUsername {
+                            username: state.username.clone(),
+                            moderator: state.moderator,
+                        };
+                        send(&mut outgoing, &packet).await?;
+
+                        // messages sent in the meantime are queued in `rx`, and may be in the
+                        // history too
+                        let mut sent = HashSet::new();
+                        for packet in
+                            chat::history(&mut connection, &room, config.chat_history).await?
+                        {
+                            if let WsPacket::ServerMessage { id, .. } = &packet {
+                                if !id.is_empty() {
+                                    sent.insert(id.clone());
+                                }
+                            }
+                            send(&mut outgoing, &packet).await?;
+                        }
+
+                        let interval = moderation::slow_mode(&mut connection, &room).await?;
+                        if interval > 0 {
+                            let packet = WsPacket::SlowMode { interval };
+                            send(&mut outgoing, &packet).await?;
+                        }
+
+                        let receive_from_others = rx
+                            .filter(move |msg| {
+                                // every message can only be queued once, so they are forgotten
+                                // as soon as they are skipped
+                                let duplicate = !sent.is_empty()
+                                    && match serde_json::from_str(msg) {
+                                        Ok(WsPacket::ServerMessage { id, .. }) => sent.remove(&id),
+                                        _ => false,
+                                    };
+                                future::ready(!duplicate)
+                            })
+                            .map(|msg| Ok(Message::Text(msg)))
+                            .forward(outgoing.clone());
+                        tokio::spawn(receive_from_others);
+                    }
+                    Action::Broadcast(message) => {
+                        let packet = WsPacket::ServerMessage {
+                            id: chat::message_id(),
+                            from: state.username.clone(),
+                            message,
+                            extra: None,
+                        };
+                        let room = state.room()?;
+                        // the video may have gone live since joining
+                        let offset = Video::get(&db, room.clone())
+                            .await?
+                            .map(|video| chat::offset(&video.status))
+                            .unwrap_or(0);
+                        chat::publish(&mut connection, room, &packet, config.chat_history, offset)
+                            .await?;
+                    }
+                    Action::CreateInvoice(amount, message) => {
+                        let amount_float = amount as f32 / 1e8;
+                        let invoice = btcpay_client
+                            .create_invoice(CreateInvoiceArgs {
+                                currency: "BTC".to_string(),
+                                price: amount_float,
+                                notification_url: Some(config.btcpay.webhook.clone()),
+                                full_notifications: Some(true),
+                                extended_notifications: Some(true),
+                                ..Default::default()
+                            })
+                            .await?;
+
+                        let webhook_data = BoostMessageInvoice {
+                            id: invoice.id.clone(),
+                            message,
+                            from: state.username.clone(),
+                            room: state.room()?.clone(),
+                        };
+                        webhook_data.save(&db).await?;
+
+                        let packet = WsPacket::Invoice { id: invoice.id };
+                        send(&mut outgoing, &packet).await?;
+                    }
+                    Action::DeleteMessage(message_id) => {
+                        let room = state.room()?;
+                        chat::delete(&mut connection, room, &message_id).await?;
+
+                        info!(
+                            "{} deleted message {} in {}",
+                            state.username, message_id, room
+                        );
+                    }
+                    Action::Timeout(username, duration) => {
+                        let ip = moderation::timeout(&mut connection, &username, duration)
+                            .await?
+                            .ok_or_else(|| {
+                                MyError::client(ErrorCode::UserNotFound, "No such user in the chat")
+                            })?;
+
+                        info!(
+                            "{} timed out {} ({}) for {}s",
+                            state.username, username, ip, duration
+                        );
+                    }
+                    Action::Ban(username) => {
+                        let ip = moderation::ban(&mut connection, &username, config.ban_duration)
+                            .await?
+                            .ok_or_else(|| {
+                                MyError::client(ErrorCode::UserNotFound, "No such user in the chat")
+                            })?;
+
+                        info!("{} banned {} ({})", state.username, username, ip);
+                    }
+                    Action::SlowMode(interval) => {
+                        let room = state.room()?;
+                        moderation::set_slow_mode(&mut connection, room, interval).await?;
+
+                        info!(
+                            "{} set slow mode to {}s in {}",
+                            state.username, interval, room
+                        );
+                    }
                 }
-                Action::CreateInvoice(amount, message) => {
-                    let amount_float = amount as f32 / 1e8;
-                    let invoice = btcpay_client
-                        .create_invoice(CreateInvoiceArgs {
-                            currency: "BTC".to_string(),
-                            price: amount_float,
-                            notification_url: Some(config.btcpay.webhook.clone()),
-                            full_notifications: Some(true),
-                            extended_notifications: Some(true),
-                            ..Default::default()
-                        })
-                        .await?;
-
-                    let webhook_data = BoostMessageInvoice {
-                        id: invoice.id.clone(),
-                        message,
-                        from: id.clone(),
-                        room: state.room.clone().ok_or(MyError::empty())?,
-                    };
-                    webhook_data.save(&db).await?;
-
-                    let packet = WsPacket::Invoice { id: invoice.id };
-                    outgoing
-                        .send(Message::Text(serde_json::to_string(&packet)?))
-                        .await?;
+
+                Ok::<(), MyError>(())
+            }
+            .await;
+
+            if let Some(packet) = response(&result, request_id) {
+                send(&mut outgoing, &packet).await?;
+            }
+            match result {
+                Err(MyError::Client {
+                    code,
+                    message,
+                    fatal,
+                    ..
+                }) => {
+                    debug!("Refused request from {}: {:?} {}", addr, code, message);
+
+                    if fatal {
+                        break;
+                    }
                 }
+                Err(MyError::Disconnected) => break,
+                // the backends may be back for the next request
+                Err(MyError::Other(e)) => error!("Request from {} failed: {:?}", addr, e),
+                Ok(()) => {}
             }
         }
 
@@ -194,6 +593,7 @@ async fn handle_connection(
     debug!("{} disconnected", &addr);
 
     db.remove(&id);
+    moderation::unregister(&mut connection, &id).await?;
 
     Ok(())
 }
@@ -208,13 +608,173 @@ pub async fn start<S: tokio::net::ToSocketAddrs + std::fmt::Display>(
     let mut listener = try_socket.expect("Failed to bind");
     info!("WebSocket Listening on: {}", addr);
 
+    let limiter = Arc::new(RateLimiter::new(config.rate_limits.clone()));
+
     while let Ok((stream, addr)) = listener.accept().await {
         tokio::spawn(handle_connection(
             db.clone(),
             btcpay_client.clone(),
             config.clone(),
+            limiter.clone(),
             stream,
             addr,
         ));
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            listen = "127.0.0.1:8080"
+            storage_dir = "/tmp"
+            base_url = ""
+            cdn_url = ""
+            redis_server = ""
+            stat_url = ""
+            admin_token = ""
+
+            [moderators]
+            alice = "secret"
+
+            [btcpay]
+            key = ""
+            url = ""
+            merchant = ""
+            webhook = ""
+        "#,
+        )
+        .unwrap()
+    }
+
+    fn joined(moderator: bool) -> State {
+        let mut state = State::new("Anon1".into(), "127.0.0.1".into());
+        state.room = Some("room".into());
+        state.moderator = moderator;
+
+        state
+    }
+
+    fn error_code(result: Result<(), MyError>) -> ErrorCode {
+        match result {
+            Err(MyError::Client { code, .. }) => code,
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_decode_request() {
+        let request: WsRequest =
+            serde_json::from_str(r#"{"ClientMessage":{"message":"hi"},"request_id":"1"}"#).unwrap();
+        match request.packet {
+            WsPacket::ClientMessage { message } => assert_eq!(message, "hi"),
+            packet => panic!("Unexpected packet {:?}", packet),
+        }
+        assert_eq!(request.request_id, Some("1".to_string()));
+
+        let request: WsRequest = serde_json::from_str(r#"{"Join":{"room":"room"}}"#).unwrap();
+        match request.packet {
+            WsPacket::Join { room, token } => {
+                assert_eq!(room, "room");
+                assert_eq!(token, None);
+            }
+            packet => panic!("Unexpected packet {:?}", packet),
+        }
+        assert_eq!(request.request_id, None);
+    }
+
+    #[test]
+    fn test_response() {
+        let ack = response(&Ok(()), Some("1".into())).unwrap();
+        assert_eq!(
+            serde_json::to_string(&ack).unwrap(),
+            r#"{"Ack":{"request_id":"1"}}"#
+        );
+        assert!(response(&Ok(()), None).is_none());
+
+        let refused = MyError::client(ErrorCode::SlowMode, "Slow mode is on").retry_after(3);
+        match response(&Err(refused), Some("2".into())) {
+            Some(WsPacket::Error {
+                code: ErrorCode::SlowMode,
+                request_id: Some(request_id),
+                retry_after: Some(3),
+                ..
+            }) => assert_eq!(request_id, "2"),
+            packet => panic!("Unexpected packet {:?}", packet),
+        }
+
+        let failed = MyError::from(std::io::Error::from(std::io::ErrorKind::Other));
+        match response(&Err(failed), None) {
+            Some(WsPacket::Error {
+                code: ErrorCode::Internal,
+                request_id: None,
+                ..
+            }) => {}
+            packet => panic!("Unexpected packet {:?}", packet),
+        }
+        assert!(response(&Err(MyError::Disconnected), Some("3".into())).is_none());
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = config();
+        let message = |message: &str| WsPacket::ClientMessage {
+            message: message.into(),
+        };
+        let timeout = |duration| WsPacket::TimeoutUser {
+            username: "Anon2".into(),
+            duration,
+        };
+
+        let state = State::new("Anon1".into(), "127.0.0.1".into());
+        assert_eq!(
+            error_code(state.validate(&message("hi"), &config)),
+            ErrorCode::NotJoined
+        );
+        let join = |token: &str| WsPacket::Join {
+            room: "room".into(),
+            token: Some(token.into()),
+        };
+        assert!(state.validate(&join("secret"), &config).is_ok());
+        assert_eq!(
+            error_code(state.validate(&join("wrong"), &config)),
+            ErrorCode::Unauthorized
+        );
+
+        let state = joined(false);
+        assert_eq!(
+            error_code(state.validate(&join("secret"), &config)),
+            ErrorCode::AlreadyJoined
+        );
+        assert!(state.validate(&message("hi"), &config).is_ok());
+        assert_eq!(
+            error_code(state.validate(&message(""), &config)),
+            ErrorCode::EmptyMessage
+        );
+        assert_eq!(
+            error_code(state.validate(&timeout(60), &config)),
+            ErrorCode::Unauthorized
+        );
+        assert_eq!(
+            error_code(state.validate(&WsPacket::UpdateViewers { viewers: 1 }, &config)),
+            ErrorCode::InvalidPacket
+        );
+
+        let state = joined(true);
+        assert!(state.validate(&timeout(60), &config).is_ok());
+        assert_eq!(
+            error_code(state.validate(&timeout(0), &config)),
+            ErrorCode::InvalidPacket
+        );
+
+        let mut state = joined(false);
+        state.read_only = true;
+        assert_eq!(
+            error_code(state.validate(&message("hi"), &config)),
+            ErrorCode::ReadOnly
+        );
+    }
+}
@@ -1,36 +1,51 @@
+function badgeStyle(amount) {
+    let width = 0;
+    let style = '';
+    let textStyle = 'white';
+    let bgColor = '';
+
+    if (amount <= 1000) {
+        width = 20;
+        style = 'info';
+        bgColor = 'rgb(47, 140, 155)';
+    } else if (amount <= 10000) {
+        width = 25;
+        style = 'primary';
+        bgColor = '#185eaa';
+    } else if (amount <= 25000) {
+        width = 40;
+        style = 'success';
+        bgColor = 'rgb(52, 155, 75)';
+    } else if (amount <= 50000) {
+        width = 60;
+        style = 'warning';
+        textStyle = 'dark';
+        bgColor = 'rgb(240, 184, 16)';
+    } else {
+        width = 100;
+        style = 'danger';
+        bgColor = 'rgb(181, 47, 59)';
+    }
+
+    return { width, style, textStyle, bgColor };
+}
+
+function styleBoosted(msg_item, msg_author, amount) {
+    const { style, textStyle } = badgeStyle(amount);
+
+    msg_item.addClass('bg-' + style).addClass('text-' + textStyle);
+    msg_author.addClass('text-' + textStyle);
+
+    msg_author.after(' +' + amount + ' <i class="fas fa-comment-dollar"></i>')
+}
+
 function DonationBadgeContainer(id) {
     const container = $('#' + id);
 
     let chat = null;
 
     this.addBadge = function(amount, duration, link) {
-        let width = 0;
-        let style = '';
-        let textStyle = 'white';
-        let bgColor = '';
-
-        if (amount <= 1000) {
-            width = 20;
-            style = 'info';
-            bgColor = 'rgb(47, 140, 155)';
-        } else if (amount <= 10000) {
-            width = 25;
-            style = 'primary';
-            bgColor = '#185eaa';
-        } else if (amount <= 25000) {
-            width = 40;
-            style = 'success';
-            bgColor = 'rgb(52, 155, 75)';
-        } else if (amount <= 50000) {
-            width = 60;
-            style = 'warning';
-            textStyle = 'dark';
-            bgColor = 'rgb(240, 184, 16)';
-        } else {
-            width = 100;
-            style = 'danger';
-            bgColor = 'rgb(181, 47, 59)';
-        }
+        const { width, style, textStyle, bgColor } = badgeStyle(amount);
 
         const item = $('<div class="progress position-relative mr-1 p-0" style="height: 4em;"></div>');
         item.css("width", width + "%");
@@ -51,14 +66,17 @@ function DonationBadgeContainer(id) {
         });
 
         container.append(item);
-
-        return { style, textStyle };
     };
 
     this.setChat = function(chatRef) {
         chat = chatRef;
     }
 
+    this.clear = function() {
+        container.find('.progress-bar').stop();
+        container.empty();
+    }
+
     return this;
 }
 
@@ -104,6 +122,8 @@ function Chat(id, textFieldId, sendButtonId, donationBadges) {
     this.connected = false;
     this.username = '';
     this.sendCb = () => {};
+    this.moderator = false;
+    this.moderateCb = () => {};
 
     const element = $('#' + id);
     const textField = $('#' + textFieldId);
@@ -155,6 +175,23 @@ function Chat(id, textFieldId, sendButtonId, donationBadges) {
         _self.sendCb(msg);
     }
 
+    // `/timeout <username> [seconds]`, `/ban <username>` and `/slow <seconds>`
+    function moderatorCommand(msg) {
+        const [command, ...args] = msg.trim().split(/\s+/);
+
+        if (command == '/timeout' && args.length >= 1) {
+            _self.moderateCb("TimeoutUser", { username: args[0], duration: parseInt(args[1] || '300') });
+        } else if (command == '/ban' && args.length == 1) {
+            _self.moderateCb("BanUser", { username: args[0] });
+        } else if (command == '/slow' && args.length == 1) {
+            _self.moderateCb("SetSlowMode", { interval: parseInt(args[0]) });
+        } else {
+            return false;
+        }
+
+        return true;
+    }
+
     this.sendMessage = function () {
         if (!_self.connected) {
             return;
@@ -163,26 +200,39 @@ function Chat(id, textFieldId, sendButtonId, donationBadges) {
         const msg = textField.val();
         textField.val('');
 
+        if (_self.moderator && moderatorCommand(msg)) {
+            return;
+        }
+
         _self.sendCb(msg);
     }
 
-    this.addMessage = function (from, msg, extra) {
+    this.addMessage = function (id, from, msg, extra) {
         let msg_author = $('<a href="#" class="author-name"></a>').text(from);
         msg_author.click(() => replyTo(msg_author.text()));
         let msg_item = $('<li class="list-group-item"></li>').text(": " + msg).prepend(msg_author);
+        msg_item.attr('data-id', id);
+
+        if (_self.moderator && id) {
+            const delete_button = $('<button type="button" class="close" aria-label="Delete">&times;</button>');
+            delete_button.click(() => _self.moderateCb("DeleteMessage", { id }));
+            msg_item.prepend(delete_button);
+        }
 
         if (msg.includes("@" + _self.username)) {
             msg_item.addClass('font-weight-bold')
         }
 
         if (extra) {
-            console.log(donationBadges);
-            const { style, textStyle } = donationBadges.addBadge(extra.amount, extra.duration, msg_item);
+            // messages from the history may have been sent a while ago
+            const elapsed = Math.max(0, Date.now() / 1000 - extra.timestamp);
+            const remaining = Math.min(extra.duration, extra.duration - elapsed);
 
-            msg_item.addClass('bg-' + style).addClass('text-' + textStyle);
-            msg_author.addClass('text-' + textStyle);
+            if (remaining > 0) {
+                donationBadges.addBadge(extra.amount, remaining, msg_item);
+            }
 
-            msg_author.after(' +' + extra.amount + ' <i class="fas fa-comment-dollar"></i>')
+            styleBoosted(msg_item, msg_author, extra.amount);
         } else if (from == _self.username) {
             msg_item.addClass('bg-light');
         }
@@ -190,6 +240,25 @@ function Chat(id, textFieldId, sendButtonId, donationBadges) {
         element.append(msg_item);
     }
 
+    this.addNotice = function (msg) {
+        element.append($('<li class="list-group-item text-muted font-italic"></li>').text(msg));
+    }
+
+    this.removeMessage = function (id) {
+        element.children().filter((_, item) => $(item).attr('data-id') == id).remove();
+    }
+
+    this.setSlowMode = function (interval) {
+        textField.attr('placeholder', interval > 0 ? `Slow mode: one message every ${interval}s` : 'Say something!');
+    }
+
+    this.clear = function () {
+        element.empty();
+        donationBadges.clear();
+        // sent again after the history, if it's still on
+        _self.setSlowMode(0);
+    }
+
     this.scrollTo = function (item) {
         window.element = element;
         window.item = item;
@@ -214,6 +283,11 @@ function Chat(id, textFieldId, sendButtonId, donationBadges) {
         _self.sendCb = sendCb;
     }
 
+    this.setModerator = function (moderator, moderateCb) {
+        _self.moderator = moderator;
+        _self.moderateCb = moderateCb;
+    }
+
     this.setConnected = function (connected) {
         if (!connected) {
             sendButton.prop('disabled', true);
@@ -234,45 +308,82 @@ function Chat(id, textFieldId, sendButtonId, donationBadges) {
     return this;
 }
 
-function Socket(url, room, onClose, chat) {
+function socketUrl() {
+    const map = { "http:": "ws://", "https:": "ws://" };
+    return map[window.location.protocol] + window.location.hostname + (window.location.port ? ":" + window.location.port : "") + "/ws";
+}
+
+// `chat` can be null for the pages that don't show it, `handlers` are called with the packets of the
+// other types, keyed by their name
+function Socket(url, room, token, onClose, chat, handlers = {}) {
     const socket = new WebSocket(url);
 
     let reqInvoiceCb = null;
+    let invoiceRequestId = null;
+    let lastRequestId = 0;
 
+    // returns the id echoed by the server in the `Ack` or `Error` answering the request
     function send(method, data) {
         let obj = {};
         obj[method] = data;
+        obj.request_id = String(++lastRequestId);
 
         console.debug(obj);
         socket.send(JSON.stringify(obj));
+
+        return obj.request_id;
     }
 
     this.getInvoice = function (amount, message, cb) {
         reqInvoiceCb = cb;
-        send("GetInvoice", { amount, message });
+        invoiceRequestId = send("GetInvoice", { amount, message });
     };
 
     socket.onopen = () => {
-        send("Join", {room});
+        send("Join", {room, token});
     };
 
     socket.onmessage = (msg) => {
         console.debug(msg.data);
         const data = JSON.parse(msg.data);
 
+        const type = Object.keys(data)[0];
+        if (handlers[type]) {
+            handlers[type](data[type]);
+            return;
+        }
+        if (!chat) {
+            return;
+        }
+
         if (data.AssignedUsername) {
+            // the history is sent again after every reconnection
+            chat.clear();
             chat.setUsername(data.AssignedUsername.username);
             chat.setSendCb((m) => { send("ClientMessage", { message: m }) });
+            chat.setModerator(data.AssignedUsername.moderator, send);
             chat.setConnected(true);
             
             chat.scrollBottom();
         } else if (data.ServerMessage) {
             const atBottom = chat.atBottom();
-            chat.addMessage(data.ServerMessage.from, data.ServerMessage.message, data.ServerMessage.extra);
+            chat.addMessage(data.ServerMessage.id, data.ServerMessage.from, data.ServerMessage.message, data.ServerMessage.extra);
 
             if (atBottom) {
                 chat.scrollBottom();
             }
+        } else if (data.MessageDeleted) {
+            chat.removeMessage(data.MessageDeleted.id);
+        } else if (data.Error) {
+            const error = data.Error;
+            if (error.request_id == invoiceRequestId) {
+                reqInvoiceCb = null;
+            }
+
+            chat.addNotice(error.retry_after ? `${error.message}, try again in ${error.retry_after}s` : error.message);
+            chat.scrollBottom();
+        } else if (data.SlowMode) {
+            chat.setSlowMode(data.SlowMode.interval);
         } else if (data.Invoice) {
             reqInvoiceCb(data.Invoice.id);
             reqInvoiceCb = null;
@@ -282,7 +393,9 @@ function Socket(url, room, onClose, chat) {
     };
 
     socket.onclose = () => {
-        chat.setConnected(false);
+        if (chat) {
+            chat.setConnected(false);
+        }
         onClose();
     };
 
@@ -296,6 +409,11 @@ function Socket(url, room, onClose, chat) {
 }
 
 $(document).ready(function() {
+    // the published videos only load this file to replay the chat
+    if (!$('#chatText').length) {
+        return;
+    }
+
     const donationBadges = new DonationBadgeContainer("donationBadgeContainer");
     const chat = new Chat("chatList", "chatText", "chatSendButton", donationBadges);
     const donateModal = new DonateModal("donateModal", "openDonateModalButton", "donateModalButton", "donateModalText", "amountValue", getInvoice, chat);
@@ -304,15 +422,18 @@ $(document).ready(function() {
     const queryString = window.location.search;
     const urlParams = new URLSearchParams(queryString);
 
+    // moderators only need to open a page with their token once
+    if (urlParams.get('token')) {
+        localStorage.setItem('moderatorToken', urlParams.get('token'));
+    }
+    const token = localStorage.getItem('moderatorToken');
+
     function getInvoice(amount, message, cb) {
         socket.getInvoice(amount, message, cb);
     }
 
     function connectSocket() {
-        const map = { "http:": "ws://", "https:": "ws://" };
-        const url = map[window.location.protocol] + window.location.hostname + (window.location.port ? ":" + window.location.port : "") + "/ws";
-        
-        socket = new Socket(url, urlParams.get('v'), () => { setTimeout(connectSocket, 1000) }, chat);
+        socket = new Socket(socketUrl(), urlParams.get('v'), token, () => { setTimeout(connectSocket, 1000) }, chat);
     }
     connectSocket();
 });
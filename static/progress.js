@@ -0,0 +1,85 @@
+function EncodingProgress(id) {
+    const container = $('#' + id);
+
+    function formatEta(eta) {
+        if (eta === null || eta === undefined || eta === '') {
+            return '';
+        }
+
+        const minutes = Math.floor(eta / 60);
+        const seconds = eta % 60;
+        return minutes > 0 ? `~${minutes}m ${seconds}s left` : `~${seconds}s left`;
+    }
+
+    // one bar for every ffmpeg run, as several groups of variants can be encoded at the same time
+    function runItem(variant) {
+        let item = container.children().filter((_, item) => $(item).attr('data-variant') == variant);
+        if (!item.length) {
+            item = $('<div></div>').attr('data-variant', variant);
+            container.append(item);
+        }
+
+        if (!item.children().length) {
+            item.append(`<div class="progress">
+                             <div class="progress-bar progress-bar-striped progress-bar-animated" role="progressbar" style="width: 0%;" aria-valuemin="0" aria-valuemax="100"></div>
+                         </div>
+                         <small class="text-muted progress-text"></small>`);
+        }
+
+        return item;
+    }
+
+    this.update = function (variant, pass, passes, progress, eta) {
+        const item = runItem(variant);
+        const percent = Math.round(progress * 100);
+
+        item.find('.progress-bar').css('width', percent + '%').attr('aria-valuenow', percent);
+        item.find('.progress-text').text(`${variant} (pass ${pass}/${passes}): ${percent}% ${formatEta(eta)}`);
+    }
+
+    container.children().each((_, item) => {
+        item = $(item);
+        this.update(item.attr('data-variant'), item.data('pass'), item.data('passes'), item.data('progress'), item.data('eta'));
+    });
+
+    return this;
+}
+
+$(document).ready(function() {
+    const progress = new EncodingProgress("encodingProgress");
+
+    const queryString = window.location.search;
+    const urlParams = new URLSearchParams(queryString);
+
+    let closed = false;
+    let delay = 1000;
+
+    const handlers = {
+        EncodingProgress: ({ variant, pass, passes, progress: value, eta }) => {
+            delay = 1000;
+            progress.update(variant, pass, passes, value, eta);
+        },
+        // the new version is ready to be watched
+        Published: () => {
+            closed = true;
+            window.location.reload();
+        },
+        Error: (error) => {
+            // the status changed while we were disconnected, reloading shows the new one
+            if (error.code == 'room_closed') {
+                closed = true;
+                window.location.reload();
+            }
+        },
+    };
+
+    function connectSocket() {
+        new Socket(socketUrl(), urlParams.get('v'), null, () => {
+            if (!closed) {
+                setTimeout(connectSocket, delay);
+                delay = Math.min(delay * 2, 60000);
+            }
+        }, null, handlers);
+    }
+    connectSocket();
+});
@@ -0,0 +1,71 @@
+function ChatReplay(id, donationBadges) {
+    donationBadges.setChat(this);
+
+    const _self = this;
+
+    const element = $('#' + id);
+
+    let messages = [];
+    let next = 0;
+    let lastTime = 0;
+
+    function addMessage(entry, time) {
+        let msg_author = $('<span class="author-name font-weight-bold"></span>').text(entry.from);
+        let msg_item = $('<li class="list-group-item"></li>').text(": " + entry.message).prepend(msg_author);
+
+        if (entry.extra) {
+            // only the part of the badge that was still visible at `time`
+            const remaining = entry.offset + entry.extra.duration - time;
+            if (remaining > 0) {
+                donationBadges.addBadge(entry.extra.amount, remaining, msg_item);
+            }
+
+            styleBoosted(msg_item, msg_author, entry.extra.amount);
+        }
+
+        element.append(msg_item);
+    }
+
+    this.load = function (url) {
+        $.getJSON(url, function (data) {
+            messages = data;
+            _self.seek(lastTime);
+        });
+    }
+
+    // shows every message sent up to `time`, starting over when seeking backwards
+    this.seek = function (time) {
+        if (time < lastTime) {
+            element.empty();
+            donationBadges.clear();
+            next = 0;
+        }
+        lastTime = time;
+
+        const atBottom = _self.atBottom();
+        while (next < messages.length && messages[next].offset <= time) {
+            addMessage(messages[next], time);
+            next++;
+        }
+
+        if (atBottom) {
+            _self.scrollBottom();
+        }
+    }
+
+    this.scrollTo = function (item) {
+        element.animate({
+            scrollTop: item.offset().top - element.offset().top + element.scrollTop()
+        });
+    }
+
+    this.atBottom = function () {
+        return element.height() + element.scrollTop() >= element.prop("scrollHeight")
+    }
+
+    this.scrollBottom = function () {
+        element.scrollTop(element.prop("scrollHeight"));
+    }
+
+    return this;
+}